@@ -0,0 +1,365 @@
+use std::sync::atomic::Ordering;
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    bandwidth::set_limit,
+    queue::{enqueue, queue_position},
+    rpc::{
+        add_link, call_ui, download_status, find_by_gid, gid, RpcError, ServerContext,
+        INTERNAL_ERROR, INVALID_PARAMS, METHOD_NOT_FOUND, UNAUTHORIZED,
+    },
+    MyApp,
+};
+
+const METHODS: &[&str] = &[
+    "aria2.addUri",
+    "aria2.tellStatus",
+    "aria2.tellActive",
+    "aria2.tellWaiting",
+    "aria2.tellStopped",
+    "aria2.pause",
+    "aria2.forcePause",
+    "aria2.pauseAll",
+    "aria2.forcePauseAll",
+    "aria2.unpause",
+    "aria2.unpauseAll",
+    "aria2.remove",
+    "aria2.forceRemove",
+    "aria2.getOption",
+    "aria2.changeOption",
+    "aria2.getGlobalStat",
+    "aria2.getVersion",
+    "system.multicall",
+    "system.listMethods",
+];
+
+// Runs on the connection thread, strips the "token:" param and forwards the rest to the ui
+pub fn handle_aria2(
+    method: &str,
+    params: Value,
+    bearer: Option<&str>,
+    server: &ServerContext,
+) -> Result<Value, RpcError> {
+    let mut params = match params {
+        Value::Array(params) => params,
+        Value::Null => Vec::new(),
+        _ => return Err(RpcError::new(INVALID_PARAMS, "Params must be an array")),
+    };
+    match method {
+        "system.listMethods" => return Ok(json!(METHODS)),
+        "system.multicall" => return multicall(params, bearer, server),
+        _ => {}
+    }
+    let token = match params.first().and_then(Value::as_str) {
+        Some(token) if token.starts_with("token:") => {
+            let token = token.trim_start_matches("token:").to_string();
+            params.remove(0);
+            Some(token)
+        }
+        _ => bearer.map(|token| token.to_string()),
+    };
+    if token.as_deref() != Some(server.token.as_str()) {
+        return Err(RpcError::new(UNAUTHORIZED, "Unauthorized"));
+    }
+    match method {
+        "aria2.addUri" => add_uri(&params, server),
+        "aria2.getVersion" => Ok(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "enabledFeatures": ["HTTPS"],
+        })),
+        _ => call_ui(server, method, Value::Array(params)),
+    }
+}
+
+fn multicall(
+    params: Vec<Value>,
+    bearer: Option<&str>,
+    server: &ServerContext,
+) -> Result<Value, RpcError> {
+    let calls = params
+        .into_iter()
+        .next()
+        .and_then(|calls| calls.as_array().cloned())
+        .ok_or(RpcError::new(INVALID_PARAMS, "Expected a list of calls"))?;
+    let results = calls
+        .into_iter()
+        .map(|call| {
+            let method = call["methodName"].as_str().unwrap_or_default().to_string();
+            if method == "system.multicall" {
+                return json!({ "code": INVALID_PARAMS, "message": "Recursive multicall" });
+            }
+            let params = call.get("params").cloned().unwrap_or(json!([]));
+            match handle_aria2(&method, params, bearer, server) {
+                Ok(result) => json!([result]),
+                Err(e) => json!({ "code": e.code, "message": e.message }),
+            }
+        })
+        .collect();
+    Ok(Value::Array(results))
+}
+
+fn add_uri(params: &[Value], server: &ServerContext) -> Result<Value, RpcError> {
+    // Every uri has to point at the same file, the ones after the first become mirrors
+    let uris = params
+        .first()
+        .and_then(Value::as_array)
+        .map(|uris| {
+            uris.iter()
+                .filter_map(Value::as_str)
+                .map(|uri| uri.to_string())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+    let (link, mirrors) = uris
+        .split_first()
+        .ok_or(RpcError::new(INVALID_PARAMS, "Missing uris"))?;
+    let bandwidth = params
+        .get(1)
+        .and_then(|options| options.get("max-download-limit"))
+        .map(|limit| parse_speed(limit).map(|bytes| bytes as f64 / 1024.0 / 1024.0))
+        .transpose()?;
+    let dir = params
+        .get(1)
+        .and_then(|options| options.get("dir"))
+        .and_then(Value::as_str);
+    let file = add_link(link, mirrors, bandwidth, dir, server)?;
+    Ok(json!(gid(&file)))
+}
+
+// aria2 accepts plain bytes or a K/M suffix, as a string or a number
+fn parse_speed(value: &Value) -> Result<usize, RpcError> {
+    let text = match value {
+        Value::String(text) => text.trim().to_string(),
+        Value::Number(number) => number.to_string(),
+        _ => return Err(RpcError::new(INVALID_PARAMS, "Invalid speed")),
+    };
+    let (number, multiplier) = match text.chars().last() {
+        Some('K') | Some('k') => (&text[..text.len() - 1], 1024),
+        Some('M') | Some('m') => (&text[..text.len() - 1], 1024 * 1024),
+        _ => (text.as_str(), 1),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Invalid speed: {}", text)))
+}
+
+fn aria2_status(app: &MyApp, index: usize) -> &'static str {
+    match download_status(&app.inner[index], queue_position(app, index).is_some()) {
+        "complete" => "complete",
+        "error" => "error",
+        "downloading" => "active",
+        "queued" => "waiting",
+        _ => "paused",
+    }
+}
+
+fn status_object(app: &MyApp, index: usize, keys: Option<&Vec<Value>>) -> Value {
+    let core = &app.inner[index];
+    let total = core.file.url.total_size;
+    let completed = core.file.size_on_disk.load(Ordering::Relaxed);
+    let active = aria2_status(app, index) == "active";
+    let speed = if active {
+        core.file.transfer_rate.load(Ordering::Relaxed)
+    } else {
+        0
+    };
+    let path = std::path::Path::new(&core.file.dir)
+        .join(&core.file.name_on_disk)
+        .to_string_lossy()
+        .to_string();
+    let mut status = json!({
+        "gid": gid(&core.file),
+        "status": aria2_status(app, index),
+        "totalLength": total.to_string(),
+        "completedLength": completed.to_string(),
+        "uploadLength": "0",
+        "downloadSpeed": speed.to_string(),
+        "uploadSpeed": "0",
+        "connections": if active { core.threads.max(1).to_string() } else { "0".to_string() },
+        "dir": core.file.dir,
+        "files": [{
+            "index": "1",
+            "path": path,
+            "length": total.to_string(),
+            "completedLength": completed.to_string(),
+            "selected": "true",
+            "uris": [{ "uri": core.file.url.link, "status": "used" }],
+        }],
+    });
+    if let Some(error) = &core.error {
+        status["errorCode"] = json!("1");
+        status["errorMessage"] = json!(error.to_string());
+    }
+    match keys {
+        Some(keys) if !keys.is_empty() => {
+            let mut filtered = Map::new();
+            for key in keys.iter().filter_map(Value::as_str) {
+                if let Some(value) = status.get(key) {
+                    filtered.insert(key.to_string(), value.clone());
+                }
+            }
+            Value::Object(filtered)
+        }
+        _ => status,
+    }
+}
+
+fn gid_at(app: &MyApp, params: &[Value], position: usize) -> Result<usize, RpcError> {
+    let gid = params
+        .get(position)
+        .and_then(Value::as_str)
+        .ok_or(RpcError::new(INVALID_PARAMS, "Missing gid"))?;
+    find_by_gid(app, gid).ok_or(RpcError::new(
+        INVALID_PARAMS,
+        format!("GID {} is not found", gid),
+    ))
+}
+
+// aria2 pages tellWaiting and tellStopped with an offset and a count
+fn list_by_status(app: &MyApp, params: &[Value], statuses: &[&str]) -> Value {
+    let offset = params.first().and_then(Value::as_u64).unwrap_or_default() as usize;
+    let num = params.get(1).and_then(Value::as_u64).unwrap_or(1000) as usize;
+    let keys = params.get(2).and_then(Value::as_array);
+    Value::Array(
+        (0..app.inner.len())
+            .filter(|index| statuses.contains(&aria2_status(app, *index)))
+            .skip(offset)
+            .take(num)
+            .map(|index| status_object(app, index, keys))
+            .collect(),
+    )
+}
+
+// Runs on the ui thread with the token already removed from the params
+pub fn dispatch_aria2(app: &mut MyApp, method: &str, params: &Value) -> Result<Value, RpcError> {
+    let empty = Vec::new();
+    let params = params.as_array().unwrap_or(&empty);
+    match method {
+        "aria2.tellStatus" => {
+            let index = gid_at(app, params, 0)?;
+            Ok(status_object(
+                app,
+                index,
+                params.get(1).and_then(Value::as_array),
+            ))
+        }
+        "aria2.tellActive" => {
+            let keys = params.first().and_then(Value::as_array);
+            Ok(Value::Array(
+                (0..app.inner.len())
+                    .filter(|index| aria2_status(app, *index) == "active")
+                    .map(|index| status_object(app, index, keys))
+                    .collect(),
+            ))
+        }
+        "aria2.tellWaiting" => Ok(list_by_status(app, params, &["waiting", "paused"])),
+        "aria2.tellStopped" => Ok(list_by_status(app, params, &["complete", "error"])),
+        "aria2.pause" | "aria2.forcePause" => {
+            let index = gid_at(app, params, 0)?;
+            let core = &mut app.inner[index];
+            core.queued = false;
+            core.file.status.0.send(false).unwrap();
+            Ok(json!(gid(&core.file)))
+        }
+        "aria2.pauseAll" | "aria2.forcePauseAll" => {
+            for core in app.inner.iter_mut() {
+                core.queued = false;
+                core.file.status.0.send(false).unwrap();
+            }
+            Ok(json!("OK"))
+        }
+        "aria2.unpause" => {
+            let index = gid_at(app, params, 0)?;
+            enqueue(&mut app.inner[index]);
+            Ok(json!(gid(&app.inner[index].file)))
+        }
+        "aria2.unpauseAll" => {
+            for core in app.inner.iter_mut() {
+                enqueue(core);
+            }
+            Ok(json!("OK"))
+        }
+        "aria2.remove" | "aria2.forceRemove" => {
+            let index = gid_at(app, params, 0)?;
+            let core = app.inner.remove(index);
+            core.file.status.0.send(false).unwrap();
+            Ok(json!(gid(&core.file)))
+        }
+        "aria2.getOption" => {
+            let index = gid_at(app, params, 0)?;
+            let core = &app.inner[index];
+            Ok(json!({
+                "dir": core.file.dir,
+                "max-download-limit": core.limit.to_string(),
+                "split": core.threads.max(1).to_string(),
+            }))
+        }
+        "aria2.changeOption" => {
+            let index = gid_at(app, params, 0)?;
+            if let Some(limit) = params
+                .get(1)
+                .and_then(|options| options.get("max-download-limit"))
+            {
+                let limit = parse_speed(limit)?;
+                set_limit(&mut app.inner[index], limit)
+                    .map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+            }
+            Ok(json!("OK"))
+        }
+        "aria2.getGlobalStat" => {
+            let statuses = (0..app.inner.len())
+                .map(|index| aria2_status(app, index))
+                .collect::<Vec<&str>>();
+            let speed = app
+                .inner
+                .iter()
+                .zip(statuses.iter())
+                .filter(|(_, status)| **status == "active")
+                .map(|(core, _)| core.file.transfer_rate.load(Ordering::Relaxed))
+                .sum::<usize>();
+            let count = |wanted: &[&str]| {
+                statuses
+                    .iter()
+                    .filter(|status| wanted.contains(status))
+                    .count()
+                    .to_string()
+            };
+            Ok(json!({
+                "downloadSpeed": speed.to_string(),
+                "uploadSpeed": "0",
+                "numActive": count(&["active"]),
+                "numWaiting": count(&["waiting", "paused"]),
+                "numStopped": count(&["complete", "error"]),
+                "numStoppedTotal": count(&["complete", "error"]),
+            }))
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method not found: {}", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speeds_take_a_suffix() {
+        assert_eq!(parse_speed(&json!("100")).unwrap(), 100);
+        assert_eq!(parse_speed(&json!("2K")).unwrap(), 2048);
+        assert_eq!(parse_speed(&json!("1m")).unwrap(), 1024 * 1024);
+        assert_eq!(parse_speed(&json!(5)).unwrap(), 5);
+    }
+
+    #[test]
+    fn overflowing_or_bad_speeds_are_rejected() {
+        assert!(parse_speed(&json!("99999999999999999M")).is_err());
+        assert!(parse_speed(&json!("-1")).is_err());
+        assert!(parse_speed(&json!("fast")).is_err());
+        assert!(parse_speed(&json!(null)).is_err());
+    }
+}
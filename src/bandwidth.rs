@@ -0,0 +1,198 @@
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use dl::file2dl::File2Dl;
+use eframe::egui::mutex::Mutex;
+
+use crate::{metadata::save_metadata, schedule::global_limit, Core, MyApp};
+
+pub fn mbs_to_bytes(mbs: f64) -> usize {
+    (mbs * 1024.0 * 1024.0) as usize
+}
+
+pub fn format_limit(bytes: usize) -> String {
+    if bytes == 0 {
+        "Unlimited".to_string()
+    } else {
+        format!("{:.2} MB/s", bytes as f64 / 1024.0 / 1024.0)
+    }
+}
+
+// Max-min fair share: downloads capped below an equal split keep their cap and what they leave is split between the rest.
+// The shares never add up to more than total, except for a cap under one byte per second per download
+pub fn fair_shares(total: usize, caps: &[usize]) -> Vec<usize> {
+    let mut order = (0..caps.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|index| match caps[*index] {
+        0 => usize::MAX,
+        cap => cap,
+    });
+    let mut shares = vec![0; caps.len()];
+    let mut remaining = total;
+    for (position, index) in order.iter().enumerate() {
+        let equal = remaining / (order.len() - position);
+        let share = match caps[*index] {
+            0 => equal,
+            cap => cap.min(equal),
+        };
+        remaining = remaining.saturating_sub(share);
+        // The engine treats 0 as unlimited
+        shares[*index] = share.max(1);
+    }
+    shares
+}
+
+// The user chosen limit is kept on the core, the engine only ever sees the share handed out here
+pub fn set_limit(core: &mut Core, limit: usize) -> Result<(), String> {
+    core.limit = limit;
+    core.file.bandwidth_chosen.store(limit, Ordering::Relaxed);
+    core.metadata.bandwidth_limit = Some(limit);
+    save_metadata(&core.file, &core.metadata)
+}
+
+// The part of the global cap our own segment downloader shares, whatever one download leaves unused the others get
+pub struct TokenBucket(Mutex<Bucket>);
+
+struct Bucket {
+    // Bytes per second, 0 for unlimited
+    rate: usize,
+    // Negative once more was taken than the bucket held, the takers wait that debt off
+    tokens: f64,
+    updated: Instant,
+}
+impl Bucket {
+    // At most a second worth of tokens is saved up, an idle bucket allows no longer burst
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self(Mutex::new(Bucket {
+            rate: 0,
+            tokens: 0.0,
+            updated: Instant::now(),
+        }))
+    }
+}
+impl TokenBucket {
+    pub fn set_rate(&self, rate: usize) {
+        let mut bucket = self.0.lock();
+        bucket.refill();
+        bucket.rate = rate;
+        if rate == 0 {
+            bucket.tokens = 0.0;
+        }
+    }
+
+    // Takes bytes that were just received and returns how long the caller has to wait before the next ones
+    pub fn take(&self, bytes: usize) -> Duration {
+        let mut bucket = self.0.lock();
+        if bucket.rate == 0 {
+            return Duration::ZERO;
+        }
+        bucket.refill();
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+        }
+    }
+}
+
+// Hands every running engine download the share it may use until the next call, the engine throttles each one on its own.
+// Downloads marked shared only keep their own cap and draw from the bucket, whose rate is returned: what the engine's shares leave of total
+pub fn split_bandwidth(total: usize, files: &[&File2Dl], caps: &[usize], shared: &[bool]) -> usize {
+    let active = files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| *file.status.1.borrow() && !file.complete.load(Ordering::Relaxed))
+        .map(|(index, _)| index)
+        .collect::<Vec<usize>>();
+    let mut targets = caps.to_vec();
+    let mut left = total;
+    if total > 0 && !active.is_empty() {
+        let active_caps = active
+            .iter()
+            .map(|index| caps[*index])
+            .collect::<Vec<usize>>();
+        for (index, share) in active.iter().zip(fair_shares(total, &active_caps)) {
+            if !shared[*index] {
+                targets[*index] = share;
+                left = left.saturating_sub(share);
+            }
+        }
+    }
+    // Written once per download so the engine never sees the unshared value in between
+    for (file, target) in files.iter().zip(targets) {
+        file.bandwidth_chosen.store(target, Ordering::Relaxed);
+    }
+    match total {
+        0 => 0,
+        _ => left.max(1),
+    }
+}
+
+pub fn process_bandwidth(app: &mut MyApp) {
+    let total = mbs_to_bytes(global_limit(app));
+    let files = app
+        .inner
+        .iter()
+        .map(|core| &core.file)
+        .collect::<Vec<&File2Dl>>();
+    let caps = app
+        .inner
+        .iter()
+        .map(|core| core.limit)
+        .collect::<Vec<usize>>();
+    let shared = app
+        .inner
+        .iter()
+        .map(|core| core.metadata.segmented())
+        .collect::<Vec<bool>>();
+    let rate = split_bandwidth(total, &files, &caps, &shared);
+    app.bucket.set_rate(rate);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncapped_downloads_split_evenly() {
+        assert_eq!(fair_shares(300, &[0, 0, 0]), vec![100, 100, 100]);
+        assert_eq!(fair_shares(100, &[]), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn what_a_capped_download_leaves_goes_to_the_others() {
+        assert_eq!(fair_shares(300, &[50, 0, 0]), vec![50, 125, 125]);
+        assert_eq!(fair_shares(300, &[0, 500, 40]), vec![130, 130, 40]);
+    }
+
+    #[test]
+    fn shares_never_exceed_total() {
+        for caps in [vec![0; 7], vec![1, 2, 3, 0, 0], vec![1000; 3], vec![0, 10]] {
+            assert!(fair_shares(1000, &caps).iter().sum::<usize>() <= 1000);
+        }
+        // Below a byte per download every one still gets the smallest limit the engine knows
+        assert_eq!(fair_shares(1, &[0, 0]), vec![1, 1]);
+    }
+
+    #[test]
+    fn bucket_makes_takers_wait_off_what_they_overdrew() {
+        let bucket = TokenBucket::default();
+        assert_eq!(bucket.take(1_000_000), Duration::ZERO);
+        bucket.set_rate(1000);
+        let wait = bucket.take(2000);
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+        // A second taker shares the same debt
+        assert!(bucket.take(1000) > wait);
+    }
+}
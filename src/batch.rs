@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use crate::{
+    credentials::Credential, errors::DownloadError, proxy::ProxySettings, request::fetch_file,
+    resolve::NewFile,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchLine {
+    // 1 based, as shown in an editor
+    pub number: usize,
+    pub url: String,
+    pub name: Option<String>,
+}
+
+// One url per line, optionally followed by the name to save it under, "#" starts a comment
+pub fn parse_batch(text: &str) -> Vec<BatchLine> {
+    text.lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            // A "#" inside a url is a fragment, only one at the start or after whitespace is a comment
+            let comment = line.match_indices('#').find(|(position, _)| {
+                *position == 0 || line[..*position].ends_with(char::is_whitespace)
+            });
+            let line = match comment {
+                Some((position, _)) => &line[..position],
+                None => line,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                return None;
+            }
+            let (url, name) = match line.split_once(char::is_whitespace) {
+                Some((url, name)) => (url, Some(name.trim().to_string())),
+                None => (line, None),
+            };
+            Some(BatchLine {
+                number: index + 1,
+                url: url.to_string(),
+                name,
+            })
+        })
+        .collect()
+}
+
+pub async fn resolve_line(
+    line: &BatchLine,
+    dir: &str,
+    bandwidth: f64,
+    credential: &Option<Credential>,
+    proxy: &ProxySettings,
+) -> Result<NewFile, DownloadError> {
+    if let Some(name) = &line.name {
+        if name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(DownloadError::Other(format!(
+                "{} is not a valid file name",
+                name
+            )));
+        }
+    }
+    let mut new = fetch_file(
+        &line.url,
+        dir,
+        bandwidth,
+        &Default::default(),
+        credential,
+        proxy,
+    )
+    .await?;
+    if let Some(name) = &line.name {
+        if *name != new.file.name_on_disk && Path::new(dir).join(name).exists() {
+            return Err(DownloadError::Other(format!("{} already exists", name)));
+        }
+        new.file.name_on_disk = name.clone();
+        new.file.url.filename = name.clone();
+    }
+    Ok(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_in_url_is_a_fragment() {
+        let lines = parse_batch("https://example.com/a.zip#section\n");
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].url, "https://example.com/a.zip#section");
+        assert_eq!(lines[0].name, None);
+    }
+
+    #[test]
+    fn hash_after_whitespace_is_a_comment() {
+        let lines = parse_batch(
+            "# mirrors\n\nhttps://example.com/a.zip  # the first one\nhttps://example.com/b.zip renamed.zip # kept\n",
+        );
+        assert_eq!(
+            lines,
+            vec![
+                BatchLine {
+                    number: 3,
+                    url: "https://example.com/a.zip".to_string(),
+                    name: None,
+                },
+                BatchLine {
+                    number: 4,
+                    url: "https://example.com/b.zip".to_string(),
+                    name: Some("renamed.zip".to_string()),
+                },
+            ]
+        );
+    }
+}
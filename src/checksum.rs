@@ -0,0 +1,301 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::DownloadError,
+    metadata::{remove_metadata, save_metadata},
+    queue::file_key,
+    request::format_headers,
+    ConfirmInterface, MyApp, Threading,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA-256",
+            HashAlgorithm::Sha1 => "SHA-1",
+            HashAlgorithm::Md5 => "MD5",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub digest: String,
+}
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.digest)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Verification {
+    #[default]
+    Unverified,
+    Verifying,
+    Verified,
+    Mismatch(String),
+    Failed(String),
+}
+
+// Accepts "sha256:<hex>", "md5 <hex>" or a bare digest whose length gives away the algorithm
+pub fn parse_checksum(input: &str) -> Result<Checksum, String> {
+    let input = input.trim();
+    let (prefix, digest) = match input.split_once([':', ' ', '=']) {
+        Some((prefix, digest)) => (Some(prefix.trim()), digest.trim()),
+        None => (None, input),
+    };
+    let digest = digest.to_ascii_lowercase();
+    if digest.is_empty() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err("Checksum must be a hexadecimal digest".to_string());
+    }
+    let algorithm = match prefix.map(|prefix| prefix.to_ascii_lowercase().replace('-', "")) {
+        Some(prefix) if prefix == "sha256" => HashAlgorithm::Sha256,
+        Some(prefix) if prefix == "sha1" => HashAlgorithm::Sha1,
+        Some(prefix) if prefix == "md5" => HashAlgorithm::Md5,
+        Some(prefix) => return Err(format!("Unsupported checksum type: {}", prefix)),
+        None => match digest.len() {
+            64 => HashAlgorithm::Sha256,
+            40 => HashAlgorithm::Sha1,
+            32 => HashAlgorithm::Md5,
+            _ => return Err("Could not guess the checksum type from its length".to_string()),
+        },
+    };
+    let expected_len = match algorithm {
+        HashAlgorithm::Sha256 => 64,
+        HashAlgorithm::Sha1 => 40,
+        HashAlgorithm::Md5 => 32,
+    };
+    if digest.len() != expected_len {
+        return Err(format!(
+            "A {} digest is {} characters long",
+            algorithm.name(),
+            expected_len
+        ));
+    }
+    Ok(Checksum { algorithm, digest })
+}
+
+fn digest_file<D: Digest>(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<String> {
+    match algorithm {
+        HashAlgorithm::Sha256 => digest_file::<Sha256>(path),
+        HashAlgorithm::Sha1 => digest_file::<Sha1>(path),
+        HashAlgorithm::Md5 => digest_file::<Md5>(path),
+    }
+}
+
+pub fn verification_of(checksum: &Checksum, computed: &str) -> Verification {
+    if checksum.digest == computed {
+        Verification::Verified
+    } else {
+        Verification::Mismatch(computed.to_string())
+    }
+}
+
+fn spawn_verification(path: PathBuf, checksum: Checksum, tx: Sender<Verification>) {
+    std::thread::spawn(move || {
+        let verification = match hash_file(&path, checksum.algorithm) {
+            Ok(computed) => verification_of(&checksum, &computed),
+            Err(e) => Verification::Failed(e.to_string()),
+        };
+        let _ = tx.send(verification);
+    });
+}
+
+// Hashes finished downloads in the background, once their parts have been merged
+pub fn process_verification(app: &mut MyApp) {
+    for core in app.inner.iter_mut() {
+        let checksum = match core.metadata.checksum.clone() {
+            Some(checksum) => checksum,
+            None => continue,
+        };
+        if core.verification == Verification::Verifying {
+            if let Ok(verification) = core.verification_channel.1.try_recv() {
+                core.metadata.computed_digest = match &verification {
+                    Verification::Verified => Some(checksum.digest.clone()),
+                    Verification::Mismatch(computed) => Some(computed.clone()),
+                    _ => None,
+                };
+                if let Err(e) = save_metadata(&core.file, &core.metadata) {
+                    app.popus.error.value = e;
+                    app.popus.error.show = true;
+                }
+                if let Verification::Mismatch(actual) = &verification {
+                    core.error = Some(DownloadError::Checksum {
+                        expected: checksum.digest.clone(),
+                        actual: actual.clone(),
+                    });
+                }
+                core.verification = verification;
+            }
+            continue;
+        }
+        let done = core
+            .file
+            .complete
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let parts = Path::new(&core.file.dir).join(format!(".{}", core.file.name_on_disk));
+        if core.verification == Verification::Unverified && done && !parts.is_dir() {
+            let path = Path::new(&core.file.dir).join(&core.file.name_on_disk);
+            spawn_verification(path, checksum, core.verification_channel.0.clone());
+            core.verification = Verification::Verifying;
+        }
+    }
+}
+
+pub fn ask_redownload(confirm: &mut ConfirmInterface, key: String) {
+    confirm.color = eframe::egui::Color32::RED;
+    confirm.text = "This will delete the file and download it again".to_string();
+    confirm.task = Box::new(move || {
+        let key = key.clone();
+        Box::new(move |app: &mut MyApp| {
+            prepare_redownload(app, &key);
+        })
+    });
+    confirm.show = true;
+}
+
+// Deletes the corrupted file and reopens the add dialog with the same settings
+pub fn prepare_redownload(app: &mut MyApp, key: &str) {
+    let index = match app
+        .inner
+        .iter()
+        .position(|core| file_key(&core.file) == key)
+    {
+        Some(index) => index,
+        None => return,
+    };
+    let core = app.inner.remove(index);
+    let dir = Path::new(&core.file.dir);
+    for path in [
+        dir.join(&core.file.name_on_disk),
+        dir.join(format!(".{}.metadata", core.file.name_on_disk)),
+    ] {
+        // The engine may have removed its metadata already, and a failed download may have no file
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                app.popus.error.value = e.to_string();
+                app.popus.error.show = true;
+            }
+            _ => {}
+        }
+    }
+    remove_metadata(&core.file);
+    let bandwidth = core.limit;
+    let download = &mut app.popus.download;
+    download.url = std::iter::once(&core.file.url.link)
+        .chain(core.metadata.mirrors.iter())
+        .cloned()
+        .collect::<Vec<String>>()
+        .join("\n");
+    download.save_to = core.file.dir.clone();
+    download.bandwidth = if bandwidth == 0 {
+        String::default()
+    } else {
+        (bandwidth as f64 / 1024.0 / 1024.0).to_string()
+    };
+    download.checksum = core
+        .metadata
+        .checksum
+        .map(|checksum| checksum.to_string())
+        .unwrap_or_default();
+    let request = &core.metadata.request;
+    download.headers = format_headers(&request.headers);
+    download.cookies = request.cookies.clone();
+    download.user_agent = request.user_agent.clone();
+    download.proxy = request.proxy.clone();
+    download.threads = core.threads.max(1).to_string();
+    download.threading = if core.threading == Threading::Multi {
+        Threading::Multi
+    } else {
+        Threading::Single
+    };
+    download.error = String::default();
+    download.show = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    const MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    #[test]
+    fn bare_digest_length_gives_the_algorithm() {
+        assert_eq!(
+            parse_checksum(SHA256).unwrap().algorithm,
+            HashAlgorithm::Sha256
+        );
+        assert_eq!(parse_checksum(SHA1).unwrap().algorithm, HashAlgorithm::Sha1);
+        assert_eq!(parse_checksum(MD5).unwrap().algorithm, HashAlgorithm::Md5);
+        assert!(parse_checksum("abcdef").is_err());
+    }
+
+    #[test]
+    fn prefixes_name_the_algorithm() {
+        let checksum = parse_checksum(&format!("sha256:{}", SHA256)).unwrap();
+        assert_eq!(checksum.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(checksum.digest, SHA256);
+        assert_eq!(
+            parse_checksum(&format!("SHA-1 {}", SHA1))
+                .unwrap()
+                .algorithm,
+            HashAlgorithm::Sha1
+        );
+        assert_eq!(
+            parse_checksum(&format!(" md5={} ", MD5)).unwrap().algorithm,
+            HashAlgorithm::Md5
+        );
+    }
+
+    #[test]
+    fn digests_are_lowercased() {
+        let checksum = parse_checksum(&format!("sha1:{}", SHA1.to_uppercase())).unwrap();
+        assert_eq!(checksum.digest, SHA1);
+    }
+
+    #[test]
+    fn invalid_input_is_an_error() {
+        assert!(parse_checksum("").is_err());
+        assert!(parse_checksum(&format!("crc32:{}", MD5)).is_err());
+        assert!(parse_checksum(&format!("sha256:{}", MD5)).is_err());
+        assert!(parse_checksum(&SHA256.replace('e', "g")).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    sync::mpsc::{channel, Receiver},
+    time::Duration,
+};
+
+use crate::{
+    checksum::{parse_checksum, Checksum, HashAlgorithm},
+    credentials::Credential,
+    metadata::save_metadata,
+    proxy::ProxySettings,
+    request::RequestOptions,
+    MyApp,
+};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+// Checksum files are tiny, anything bigger is not what we are looking for
+const MAX_SIDECAR_SIZE: usize = 1024 * 1024;
+
+struct Candidate {
+    url: String,
+    algorithm: HashAlgorithm,
+    // Per file sidecars may hold a single digest without a filename
+    single_file: bool,
+}
+
+fn candidates(link: &str) -> Vec<Candidate> {
+    let base = link.split(['?', '#']).next().unwrap_or(link);
+    let dir = match base.rfind('/') {
+        Some(index) => &base[..index],
+        None => return Vec::new(),
+    };
+    let mut candidates = Vec::new();
+    for (extension, algorithm) in [
+        ("sha256", HashAlgorithm::Sha256),
+        ("sha1", HashAlgorithm::Sha1),
+        ("md5", HashAlgorithm::Md5),
+    ] {
+        candidates.push(Candidate {
+            url: format!("{}.{}", base, extension),
+            algorithm,
+            single_file: true,
+        });
+    }
+    for (list, algorithm) in [
+        ("SHA256SUMS", HashAlgorithm::Sha256),
+        ("SHA1SUMS", HashAlgorithm::Sha1),
+        ("MD5SUMS", HashAlgorithm::Md5),
+    ] {
+        candidates.push(Candidate {
+            url: format!("{}/{}", dir, list),
+            algorithm,
+            single_file: false,
+        });
+    }
+    candidates
+}
+
+fn file_name_of(link: &str) -> String {
+    let base = link.split(['?', '#']).next().unwrap_or(link);
+    base.rsplit('/').next().unwrap_or_default().to_string()
+}
+
+fn same_file(listed: &str, names: &[&str]) -> bool {
+    let listed = listed.trim().trim_start_matches("./");
+    let listed = listed.rsplit('/').next().unwrap_or(listed);
+    names.contains(&listed)
+}
+
+// Understands GNU coreutils ("<digest>  name" or "<digest> *name") and BSD tags ("SHA256 (name) = <digest>")
+pub fn parse_sidecar(
+    content: &str,
+    algorithm: HashAlgorithm,
+    names: &[&str],
+    single_file: bool,
+) -> Option<Checksum> {
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let line = line.trim().trim_start_matches('\\');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((tag, rest)) = line.split_once(" (") {
+            if let Some((name, digest)) = rest.rsplit_once(") = ") {
+                if let Ok(checksum) = parse_checksum(&format!("{}:{}", tag, digest)) {
+                    entries.push((Some(name.to_string()), checksum));
+                }
+                continue;
+            }
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let digest = parts.next().unwrap_or_default();
+        let name = parts
+            .next()
+            .map(|name| name.trim().trim_start_matches('*').to_string());
+        if let Ok(checksum) = parse_checksum(&format!("{}:{}", algorithm.name(), digest)) {
+            entries.push((name, checksum));
+        }
+    }
+    if let Some((_, checksum)) = entries
+        .iter()
+        .find(|(name, _)| name.as_deref().is_some_and(|name| same_file(name, names)))
+    {
+        return Some(checksum.clone());
+    }
+    match entries.as_slice() {
+        [(_, checksum)] if single_file => Some(checksum.clone()),
+        _ => None,
+    }
+}
+
+async fn fetch_sidecar(
+    client: &reqwest::Client,
+    url: &str,
+    credential: &Option<Credential>,
+) -> Option<String> {
+    let mut request = client.get(url);
+    if let Some(credential) = credential {
+        request = credential.apply(request);
+    }
+    let response = request.send().await.ok()?;
+    if !response.status().is_success() {
+        return None;
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length as usize > MAX_SIDECAR_SIZE)
+    {
+        return None;
+    }
+    let content = response.text().await.ok()?;
+    if content.len() > MAX_SIDECAR_SIZE {
+        return None;
+    }
+    Some(content)
+}
+
+// Asks the way the download itself does, a sidecar behind the same login needs the same headers
+pub async fn discover_checksum(
+    link: &str,
+    name_on_disk: &str,
+    request: &RequestOptions,
+    credential: &Option<Credential>,
+    proxy: &ProxySettings,
+) -> Option<Checksum> {
+    let client = request
+        .client_builder(proxy)
+        .ok()?
+        .timeout(PROBE_TIMEOUT)
+        .build()
+        .ok()?;
+    let url_name = file_name_of(link);
+    let names = [url_name.as_str(), name_on_disk];
+    for candidate in candidates(link) {
+        if let Some(content) = fetch_sidecar(&client, &candidate.url, credential).await {
+            let found = parse_sidecar(&content, candidate.algorithm, &names, candidate.single_file);
+            if found.is_some() {
+                return found;
+            }
+        }
+    }
+    None
+}
+
+pub fn spawn_discovery(
+    runtime: &tokio::runtime::Handle,
+    link: String,
+    name_on_disk: String,
+    request: RequestOptions,
+    credential: Option<Credential>,
+    proxy: ProxySettings,
+) -> Receiver<Option<Checksum>> {
+    let (tx, rx) = channel();
+    runtime.spawn(async move {
+        let checksum = discover_checksum(&link, &name_on_disk, &request, &credential, &proxy).await;
+        let _ = tx.send(checksum);
+    });
+    rx
+}
+
+pub fn process_discovery(app: &mut MyApp) {
+    for core in app.inner.iter_mut() {
+        let result = match &core.discovery {
+            Some(rx) => match rx.try_recv() {
+                Ok(result) => result,
+                Err(std::sync::mpsc::TryRecvError::Empty) => continue,
+                Err(std::sync::mpsc::TryRecvError::Disconnected) => None,
+            },
+            None => continue,
+        };
+        core.discovery = None;
+        if let Some(checksum) = result {
+            if core.metadata.checksum.is_none() {
+                core.metadata.checksum = Some(checksum);
+                if let Err(e) = save_metadata(&core.file, &core.metadata) {
+                    app.popus.error.value = e;
+                    app.popus.error.show = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const OTHER: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn sha256(content: &str, single_file: bool) -> Option<String> {
+        parse_sidecar(content, HashAlgorithm::Sha256, &["file.iso"], single_file)
+            .map(|checksum| checksum.digest)
+    }
+
+    #[test]
+    fn gnu_lines_match_the_file_name() {
+        let content = format!("{}  other.iso\n{}  file.iso\n", OTHER, SHA256);
+        assert_eq!(sha256(&content, false).as_deref(), Some(SHA256));
+    }
+
+    #[test]
+    fn binary_marker_is_ignored() {
+        let content = format!("{} *other.iso\n{} *file.iso\n", OTHER, SHA256);
+        assert_eq!(sha256(&content, false).as_deref(), Some(SHA256));
+    }
+
+    #[test]
+    fn bsd_tags_match_the_file_name() {
+        let content = format!(
+            "SHA256 (other.iso) = {}\nSHA256 (dist/file.iso) = {}\n",
+            OTHER, SHA256
+        );
+        assert_eq!(sha256(&content, false).as_deref(), Some(SHA256));
+    }
+
+    #[test]
+    fn a_lone_digest_only_counts_for_a_per_file_sidecar() {
+        assert_eq!(sha256(SHA256, true).as_deref(), Some(SHA256));
+        assert_eq!(sha256(SHA256, false), None);
+        let content = format!("{}  other.iso\n", OTHER);
+        assert_eq!(sha256(&content, false), None);
+    }
+}
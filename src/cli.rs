@@ -0,0 +1,621 @@
+use std::{
+    fs::{remove_dir_all, remove_file},
+    io::Write,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use dl::{
+    file2dl::{Download, File2Dl},
+    utils::count_files,
+};
+use futures_util::future::join_all;
+use tokio::runtime::Runtime;
+
+use crate::{
+    bandwidth::{mbs_to_bytes, split_bandwidth, TokenBucket},
+    config::{load_downloads, load_settings, prepare_dir},
+    credentials::load_credentials,
+    errors::DownloadError,
+    metadata::{load_metadata, remove_metadata, save_metadata, Metadata},
+    mirrors::{done_bytes, job_for, mirror_dl, mirror_part_path, take_over, MirrorJob},
+    queue::{file_key, remove_saved, saved_queued, set_saved_queued},
+    request::fetch_file,
+    retry::RetryPolicy,
+    Threading,
+};
+
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_PARTIAL: i32 = 2;
+pub const EXIT_NETWORK: i32 = 3;
+pub const EXIT_USAGE: i32 = 64;
+
+const USAGE: &str = "Usage: dl <command> [options]
+
+Commands:
+  add <url> [--bandwidth <Mbs>] [--threads <n>] [--dir <path>] [--no-start]
+                              Add a download and run it until it finishes,
+                              --dir overrides the default download directory,
+                              --no-start only queues it for the GUI
+  list                        List every download in every used directory
+  resume <name|index>... | --all
+                              Run paused downloads until they finish
+  pause <name|index>... | --all
+                              Take downloads out of the saved queue, a running
+                              GUI only notices on its next start
+  remove <name|index>... [--delete]
+                              Forget downloads, --delete also removes the file
+                              and its downloaded parts
+
+Running without a command starts the GUI.
+
+Exit codes:
+  0   every download finished
+  1   every download failed or the command could not run
+  2   some downloads finished and some failed
+  3   downloads failed because of the network
+  64  invalid usage";
+
+#[derive(Default)]
+struct Args {
+    positional: Vec<String>,
+    bandwidth: Option<f64>,
+    threads: Option<usize>,
+    dir: Option<String>,
+    all: bool,
+    delete: bool,
+    no_start: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Done,
+    Failed,
+    NetworkFailed,
+}
+
+struct Job {
+    file: File2Dl,
+    threading: Threading,
+    threads: usize,
+    // Set when the segment downloader runs it instead of the engine
+    metadata: Option<Metadata>,
+}
+
+pub fn run(args: &[String]) -> i32 {
+    let (command, rest) = match args.split_first() {
+        Some(split) => split,
+        None => {
+            println!("{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    let args = match parse_args(rest) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    match command.as_str() {
+        "add" => add(args),
+        "list" => list(),
+        "resume" => resume(args),
+        "pause" => pause(args),
+        "remove" => remove(args),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            EXIT_SUCCESS
+        }
+        _ => {
+            eprintln!("Unknown command: {command}\n\n{USAGE}");
+            EXIT_USAGE
+        }
+    }
+}
+
+fn parse_args(raw: &[String]) -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut iter = raw.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--bandwidth" => {
+                let value = iter.next().ok_or("--bandwidth needs a value")?;
+                let bandwidth = value
+                    .parse::<f64>()
+                    .map_err(|_| format!("Invalid bandwidth: {value}"))?;
+                args.bandwidth = Some(bandwidth);
+            }
+            "--threads" => {
+                let value = iter.next().ok_or("--threads needs a value")?;
+                match value.parse::<usize>() {
+                    Ok(threads) if threads > 0 => args.threads = Some(threads),
+                    _ => return Err(format!("Invalid number of threads: {value}")),
+                }
+            }
+            "--dir" => {
+                let value = iter.next().ok_or("--dir needs a value")?;
+                args.dir = Some(value.to_owned());
+            }
+            "--all" => args.all = true,
+            "--delete" => args.delete = true,
+            "--no-start" => args.no_start = true,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option: {flag}")),
+            _ => args.positional.push(arg.to_owned()),
+        }
+    }
+    Ok(args)
+}
+
+fn load_files() -> Result<Vec<File2Dl>, String> {
+    let (mut files, errors) = load_downloads(&load_settings());
+    if let Some(e) = errors.first() {
+        return Err(e.to_owned());
+    }
+    // The engine knows nothing about chunks fetched by the segment downloader
+    for file in files.iter_mut() {
+        let metadata = load_metadata(file);
+        if metadata.segmented() && !file.complete.load(Ordering::Relaxed) {
+            if let Some(total_size) = metadata.total_size {
+                file.url.total_size = total_size;
+            }
+            file.size_on_disk.store(
+                done_bytes(&metadata.mirror_chunks, file.url.total_size),
+                Ordering::Relaxed,
+            );
+        }
+    }
+    Ok(files)
+}
+
+// Same detection the GUI uses when it reloads the list
+fn threading_of(file: &File2Dl) -> (Threading, usize) {
+    let threading = if Path::new(&file.dir).join(&file.name_on_disk).is_dir() {
+        Threading::Multi
+    } else {
+        Threading::Single
+    };
+    let threads = count_files(&format!("{}/.{}", file.dir, file.name_on_disk)).unwrap_or_default();
+    (threading, threads)
+}
+
+fn select(files: Vec<File2Dl>, args: &Args) -> Result<Vec<File2Dl>, String> {
+    if args.all {
+        return Ok(files);
+    }
+    if args.positional.is_empty() {
+        return Err("Specify downloads by name or index, or pass --all".to_string());
+    }
+    let mut selected = Vec::new();
+    for target in args.positional.iter() {
+        let found = match target.parse::<usize>() {
+            Ok(index) if index > 0 => files.get(index - 1),
+            _ => files.iter().find(|file| file.name_on_disk == *target),
+        };
+        match found {
+            Some(file) => selected.push(file.clone()),
+            None => return Err(format!("No download matches {target}")),
+        }
+    }
+    Ok(selected)
+}
+
+fn add(args: Args) -> i32 {
+    let link = match args.positional.as_slice() {
+        [link] => link.to_owned(),
+        _ => {
+            eprintln!("add takes exactly one url\n\n{USAGE}");
+            return EXIT_USAGE;
+        }
+    };
+    let existing = match load_files() {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    };
+    for file in existing.iter() {
+        if file.url.link == link && !file.complete.load(Ordering::Relaxed) {
+            eprintln!(
+                "Download already exists as {}, simply resume it",
+                file.name_on_disk
+            );
+            return EXIT_FAILURE;
+        }
+    }
+    let settings = load_settings();
+    let dir = args.dir.unwrap_or(settings.download_dir);
+    let dir = match prepare_dir(&dir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    };
+    let rt = Runtime::new().unwrap();
+    let bandwidth = args.bandwidth.unwrap_or(settings.default_bandwidth);
+    let credential = load_credentials().for_link(&link);
+    // Same as the add dialog, with a proxy or credentials the file is probed by our own request
+    let new = match rt.block_on(fetch_file(
+        &link,
+        &dir,
+        bandwidth,
+        &Default::default(),
+        &credential,
+        &settings.proxy,
+    )) {
+        Ok(new) => new,
+        Err(e) => {
+            eprintln!("{e}");
+            return if e.is_network() {
+                EXIT_NETWORK
+            } else {
+                EXIT_FAILURE
+            };
+        }
+    };
+    let file = new.file;
+    let metadata = new.probed.then(|| Metadata {
+        link: file.url.link.clone(),
+        content_type: file.url.content_type.clone(),
+        total_size: Some(file.url.total_size),
+        bandwidth_limit: Some(mbs_to_bytes(bandwidth)),
+        authenticated: credential.is_some(),
+        ..Default::default()
+    });
+    if let Some(metadata) = &metadata {
+        if let Err(e) = save_metadata(&file, metadata) {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    }
+    println!("Added {}", file.name_on_disk);
+    if args.no_start {
+        if let Err(e) = set_saved_queued(&file_key(&file), true) {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+        return EXIT_SUCCESS;
+    }
+    let threads = args.threads.unwrap_or(settings.default_threads);
+    let job = Job {
+        file,
+        threading: Threading::for_threads(threads),
+        threads,
+        metadata,
+    };
+    exit_code(&rt.block_on(run_downloads(vec![job])))
+}
+
+fn list() -> i32 {
+    let files = match load_files() {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+    };
+    let queued = saved_queued();
+    for (index, file) in files.iter().enumerate() {
+        let progress = file.size_on_disk.load(Ordering::Relaxed);
+        let status = if file.complete.load(Ordering::Relaxed) {
+            "complete"
+        } else if queued.contains(&file_key(file)) {
+            "queued"
+        } else {
+            "paused"
+        };
+        println!(
+            "{:>3}  {:<8}  {}  {}",
+            index + 1,
+            status,
+            format_progress(progress, file.url.total_size),
+            file.name_on_disk
+        );
+    }
+    EXIT_SUCCESS
+}
+
+fn resume(args: Args) -> i32 {
+    let files = match load_files().and_then(|files| select(files, &args)) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_USAGE;
+        }
+    };
+    let settings = load_settings();
+    let mut jobs = Vec::new();
+    let mut outcomes = Vec::new();
+    for file in files
+        .into_iter()
+        .filter(|file| !file.complete.load(Ordering::Relaxed))
+    {
+        let mut metadata = load_metadata(&file);
+        // The engine never goes through a proxy, with one set every download is fetched in chunks
+        if settings.proxy.is_set() && !metadata.segmented() {
+            if let Err(e) = take_over(&file, &mut metadata) {
+                eprintln!("{}: {}", file.name_on_disk, e);
+                outcomes.push(Outcome::Failed);
+                continue;
+            }
+        }
+        let job = if metadata.segmented() {
+            Job {
+                file,
+                threading: Threading::Multi,
+                threads: settings.default_threads,
+                metadata: Some(metadata),
+            }
+        } else {
+            let (threading, threads) = threading_of(&file);
+            Job {
+                file,
+                threading,
+                threads,
+                metadata: None,
+            }
+        };
+        jobs.push(job);
+    }
+    if jobs.is_empty() && outcomes.is_empty() {
+        println!("Nothing to resume");
+        return EXIT_SUCCESS;
+    }
+    let rt = Runtime::new().unwrap();
+    outcomes.extend(rt.block_on(run_downloads(jobs)));
+    exit_code(&outcomes)
+}
+
+fn pause(args: Args) -> i32 {
+    let files = match load_files().and_then(|files| select(files, &args)) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_USAGE;
+        }
+    };
+    for file in files.iter() {
+        if let Err(e) = set_saved_queued(&file_key(file), false) {
+            eprintln!("{e}");
+            return EXIT_FAILURE;
+        }
+        println!("Paused {}", file.name_on_disk);
+    }
+    EXIT_SUCCESS
+}
+
+fn remove(args: Args) -> i32 {
+    let files = match load_files().and_then(|files| select(files, &args)) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("{e}");
+            return EXIT_USAGE;
+        }
+    };
+    let mut failed = 0;
+    for file in files.iter() {
+        let dir = Path::new(&file.dir);
+        let metadata = dir.join(format!(".{}.metadata", file.name_on_disk));
+        let parts = dir.join(format!(".{}", file.name_on_disk));
+        // A file built from our own request only has our metadata
+        let mut result = Ok(());
+        if metadata.is_file() {
+            result = remove_file(metadata);
+        }
+        remove_metadata(file);
+        // Without --delete nothing downloaded so far is lost, only forgotten
+        if args.delete {
+            if parts.is_dir() {
+                result = result.and(remove_dir_all(parts));
+            }
+            let _ = remove_file(mirror_part_path(file));
+            let target = dir.join(&file.name_on_disk);
+            if target.is_file() {
+                result = result.and(remove_file(target));
+            }
+        }
+        match result
+            .map_err(|e| e.to_string())
+            .and(remove_saved(&file_key(file)))
+        {
+            Ok(_) => println!("Removed {}", file.name_on_disk),
+            Err(e) => {
+                eprintln!("{}: {}", file.name_on_disk, e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => EXIT_SUCCESS,
+        failed if failed == files.len() => EXIT_FAILURE,
+        _ => EXIT_PARTIAL,
+    }
+}
+
+async fn run_downloads(jobs: Vec<Job>) -> Vec<Outcome> {
+    let settings = load_settings();
+    let credentials = load_credentials();
+    let bucket = Arc::new(TokenBucket::default());
+    let files = jobs
+        .iter()
+        .map(|job| job.file.clone())
+        .collect::<Vec<File2Dl>>();
+    let shared = jobs
+        .iter()
+        .map(|job| job.metadata.is_some())
+        .collect::<Vec<bool>>();
+    // The same split of the global cap the GUI makes, redone as downloads finish
+    let total = mbs_to_bytes(settings.global_bandwidth);
+    let caps = files
+        .iter()
+        .map(|file| file.bandwidth_chosen.load(Ordering::Relaxed))
+        .collect::<Vec<usize>>();
+    // Every download is about to start, so they all get a share before the first one does
+    for file in files.iter() {
+        let _ = file.status.0.send(true);
+    }
+    bucket.set_rate(split_bandwidth(
+        total,
+        &files.iter().collect::<Vec<&File2Dl>>(),
+        &caps,
+        &shared,
+    ));
+    let printer = {
+        let bucket = bucket.clone();
+        tokio::spawn(async move {
+            let files = files.iter().collect::<Vec<&File2Dl>>();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                bucket.set_rate(split_bandwidth(total, &files, &caps, &shared));
+                print_progress(&files);
+            }
+        })
+    };
+    let outcomes = join_all(jobs.into_iter().map(|job| {
+        let mirrors = job.metadata.map(|metadata| {
+            let mirror_job = job_for(
+                &job.file,
+                &metadata,
+                Arc::default(),
+                &credentials,
+                &settings.proxy,
+                &bucket,
+            );
+            (mirror_job, metadata)
+        });
+        download(job.file, job.threading, job.threads, mirrors)
+    }))
+    .await;
+    printer.abort();
+    println!();
+    outcomes
+}
+
+// Keeps the chunks the segment downloader finished, an interrupted run picks up from them
+fn save_chunks(file: &File2Dl, job: &MirrorJob, metadata: &mut Metadata) {
+    let done = job.done.lock().clone();
+    if done.len() != metadata.mirror_chunks.len() {
+        metadata.mirror_chunks = done;
+        let _ = save_metadata(file, metadata);
+    }
+}
+
+async fn download(
+    mut file: File2Dl,
+    threading: Threading,
+    threads: usize,
+    mirrors: Option<(MirrorJob, Metadata)>,
+) -> Outcome {
+    file.status.0.send(true).unwrap();
+    let single = mirrors.is_none()
+        && threading == Threading::Single
+        && Path::new(&file.dir).join(&file.name_on_disk).is_file();
+    let policy = RetryPolicy::from_settings(&load_settings());
+    let (tx, _rx) = std::sync::mpsc::channel();
+    let saver = mirrors.clone().map(|(job, mut metadata)| {
+        let file = file.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                save_chunks(&file, &job, &mut metadata);
+            }
+        })
+    });
+    let mut attempt = 0;
+    let outcome = loop {
+        let result = if let Some((job, _)) = &mirrors {
+            mirror_dl(&file, threads.max(1), job, &tx).await
+        } else if single {
+            file.single_thread_dl()
+                .await
+                .map_err(|e| DownloadError::from_error(&e))
+        } else {
+            file.multi_thread_dl(threads.max(1))
+                .await
+                .map_err(|e| DownloadError::from_error(&e))
+        };
+        let error = match result {
+            Ok(_) => break Outcome::Done,
+            Err(error) => error,
+        };
+        attempt += 1;
+        if error.is_retryable() && attempt < policy.max_attempts {
+            let delay = policy.delay(attempt);
+            eprintln!(
+                "\n{}: {} (retrying in {}s, attempt {}/{})",
+                file.name_on_disk,
+                error,
+                delay.as_secs(),
+                attempt,
+                policy.max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            continue;
+        }
+        eprintln!("\n{}: {}", file.name_on_disk, error);
+        break if error.is_network() {
+            Outcome::NetworkFailed
+        } else {
+            Outcome::Failed
+        };
+    };
+    if let (Some(saver), Some((job, mut metadata))) = (saver, mirrors) {
+        saver.abort();
+        save_chunks(&file, &job, &mut metadata);
+    }
+    outcome
+}
+
+fn print_progress(files: &[&File2Dl]) {
+    let line = files
+        .iter()
+        .map(|file| {
+            let progress = file.size_on_disk.load(Ordering::Relaxed);
+            let rate = if file.complete.load(Ordering::Relaxed) {
+                0
+            } else {
+                file.transfer_rate.load(Ordering::Relaxed)
+            };
+            format!(
+                "{} {} {:.2} MB/s",
+                file.name_on_disk,
+                format_progress(progress, file.url.total_size),
+                rate as f64 / 1024.0 / 1024.0
+            )
+        })
+        .collect::<Vec<String>>()
+        .join(" | ");
+    print!("\r{line}");
+    let _ = std::io::stdout().flush();
+}
+
+fn format_progress(progress: usize, total: usize) -> String {
+    let percentage = if total == 0 {
+        0.0
+    } else {
+        progress as f64 / total as f64 * 100.0
+    };
+    format!(
+        "{:>6.2}% {:.3}MB/{:.3}MB",
+        percentage,
+        progress as f64 / 1024.0 / 1024.0,
+        total as f64 / 1024.0 / 1024.0
+    )
+}
+
+fn exit_code(outcomes: &[Outcome]) -> i32 {
+    let done = outcomes.iter().filter(|o| **o == Outcome::Done).count();
+    if done == outcomes.len() {
+        EXIT_SUCCESS
+    } else if done > 0 {
+        EXIT_PARTIAL
+    } else if outcomes.contains(&Outcome::NetworkFailed) {
+        EXIT_NETWORK
+    } else {
+        EXIT_FAILURE
+    }
+}
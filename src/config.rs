@@ -0,0 +1,239 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use dl::file2dl::File2Dl;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    connectivity::ProbeMode, metadata::own_files, proxy::ProxySettings, retry::DEFAULT_MAX_ATTEMPTS,
+};
+
+const APP_DIR: &str = "dl";
+const SETTINGS_FILE: &str = "settings.json";
+const DIRS_FILE: &str = "dirs.json";
+// Where downloads went before the directory was configurable
+const LEGACY_DIR: &str = "Downloads";
+pub const MAX_THREADS: usize = 64;
+pub const MAX_ATTEMPTS: u32 = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub download_dir: String,
+    pub probe_mode: ProbeMode,
+    pub probe_host: String,
+    pub probe_port: u16,
+    pub probe_url: String,
+    // Pauses running downloads while offline and resumes them afterwards
+    pub pause_offline: bool,
+    // Downloads that were running when the app last closed start again
+    pub resume_on_startup: bool,
+    pub default_threads: usize,
+    // In Mbs, 0 means unlimited
+    pub default_bandwidth: f64,
+    // In Mbs shared by every running download, 0 means unlimited
+    pub global_bandwidth: f64,
+    // Tries per download before it is marked failed
+    pub max_attempts: u32,
+    pub proxy: ProxySettings,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            download_dir: default_download_dir(),
+            probe_mode: ProbeMode::default(),
+            probe_host: "8.8.8.8".to_string(),
+            probe_port: 53,
+            probe_url: String::default(),
+            pause_offline: true,
+            resume_on_startup: false,
+            default_threads: 1,
+            default_bandwidth: 0.0,
+            global_bandwidth: 0.0,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            proxy: ProxySettings::default(),
+        }
+    }
+}
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.download_dir.trim().is_empty() {
+            return Err("Download folder cannot be empty".to_string());
+        }
+        match self.probe_mode {
+            ProbeMode::Tcp if self.probe_host.trim().is_empty() => {
+                return Err("Connectivity host cannot be empty".to_string());
+            }
+            ProbeMode::Tcp if self.probe_port == 0 => {
+                return Err("Connectivity port cannot be 0".to_string());
+            }
+            ProbeMode::Http
+                if !reqwest::Url::parse(self.probe_url.trim())
+                    .is_ok_and(|url| matches!(url.scheme(), "http" | "https")) =>
+            {
+                return Err("Connectivity URL must be http:// or https://".to_string());
+            }
+            _ => {}
+        }
+        if !(1..=MAX_THREADS).contains(&self.default_threads) {
+            return Err(format!("Threads must be between 1 and {}", MAX_THREADS));
+        }
+        if !self.default_bandwidth.is_finite() || self.default_bandwidth < 0.0 {
+            return Err("Bandwidth cannot be negative".to_string());
+        }
+        if !self.global_bandwidth.is_finite() || self.global_bandwidth < 0.0 {
+            return Err("Global bandwidth cannot be negative".to_string());
+        }
+        if !(1..=MAX_ATTEMPTS).contains(&self.max_attempts) {
+            return Err(format!(
+                "Retry attempts must be between 1 and {}",
+                MAX_ATTEMPTS
+            ));
+        }
+        self.proxy.validate()
+    }
+
+    pub fn probe_address(&self) -> String {
+        format!("{}:{}", self.probe_host.trim(), self.probe_port)
+    }
+
+    // Behind a proxy the probe host is usually firewalled, being online then means reaching the proxy
+    pub fn check_address(&self) -> String {
+        match self.proxy.address() {
+            Some(proxy) if !self.proxy.bypasses(&self.probe_host) => proxy,
+            _ => self.probe_address(),
+        }
+    }
+}
+
+fn default_download_dir() -> String {
+    dirs::download_dir()
+        .map(|dir| dir.join(APP_DIR))
+        .unwrap_or_else(|| PathBuf::from(LEGACY_DIR))
+        .to_string_lossy()
+        .to_string()
+}
+
+// App wide state lives in the user config dir so it does not depend on the working directory
+pub fn config_path(name: &str) -> PathBuf {
+    dirs::config_dir()
+        .map(|dir| dir.join(APP_DIR))
+        .unwrap_or_default()
+        .join(name)
+}
+
+pub fn write_config_file(name: &str, content: &str) -> Result<(), String> {
+    let path = config_path(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, content).map_err(|e| e.to_string())
+}
+
+// Only the owner may read it, and it is never seen half written or with wider permissions
+pub fn write_private_config_file(name: &str, content: &str) -> Result<(), String> {
+    let path = config_path(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let temp = config_path(&format!(".{}.tmp", name));
+    let _ = fs::remove_file(&temp);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let result = options
+        .open(&temp)
+        .and_then(|mut file| {
+            file.write_all(content.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, &path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|e| e.to_string())
+}
+
+pub fn load_settings() -> Settings {
+    fs::read_to_string(config_path(SETTINGS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Settings>(&content).ok())
+        // A hand edited file with bad values should not leave the app unusable
+        .filter(|settings| settings.validate().is_ok())
+        .unwrap_or_default()
+}
+
+pub fn save_settings(settings: &Settings) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+    // May hold the proxy password
+    write_private_config_file(SETTINGS_FILE, &json)
+}
+
+pub fn absolute_dir(dir: &str) -> String {
+    std::path::absolute(dir)
+        .unwrap_or_else(|_| PathBuf::from(dir))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn known_dirs() -> Vec<String> {
+    fs::read_to_string(config_path(DIRS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Vec<String>>(&content).ok())
+        .unwrap_or_default()
+}
+
+// Every directory a download was saved to, so the list can be rebuilt on the next start
+pub fn remember_dir(dir: &str) -> Result<(), String> {
+    let mut dirs = known_dirs();
+    if dirs.iter().any(|known| known == dir) {
+        return Ok(());
+    }
+    dirs.push(dir.to_string());
+    let json = serde_json::to_string_pretty(&dirs).map_err(|e| e.to_string())?;
+    write_config_file(DIRS_FILE, &json)
+}
+
+// Creates the directory and returns the absolute path the download should be saved to
+pub fn prepare_dir(dir: &str) -> Result<String, String> {
+    let dir = absolute_dir(dir.trim());
+    fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {}: {}", dir, e))?;
+    remember_dir(&dir)?;
+    Ok(dir)
+}
+
+pub fn download_dirs(settings: &Settings) -> Vec<String> {
+    let mut dirs = known_dirs();
+    for dir in [LEGACY_DIR, settings.download_dir.as_str()] {
+        let dir = absolute_dir(dir);
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs.retain(|dir| Path::new(dir).is_dir());
+    dirs
+}
+
+// A directory that fails to load does not keep the others from showing up
+pub fn load_downloads(settings: &Settings) -> (Vec<File2Dl>, Vec<String>) {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    for dir in download_dirs(settings) {
+        match File2Dl::from(&dir) {
+            Ok(loaded) => {
+                let own = own_files(&dir, &loaded);
+                files.extend(loaded);
+                files.extend(own);
+            }
+            Err(e) => errors.push(format!("{}: {}", dir, e)),
+        }
+    }
+    (files, errors)
+}
@@ -0,0 +1,151 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    sync::atomic::Ordering,
+    thread::sleep,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Settings,
+    history::now,
+    proxy::ProxySettings,
+    queue::{file_key, is_active},
+    MyApp,
+};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// A single lost probe is not worth pausing every download for
+const OFFLINE_AFTER: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProbeMode {
+    // Connects to the probe host and port
+    #[default]
+    Tcp,
+    // Sends a HEAD request to the probe url, any answer counts
+    Http,
+    // Leaves it to the OS, downloads still retry on network errors
+    AssumeOnline,
+}
+
+// What the background check does, rebuilt whenever the settings change
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probe {
+    Tcp(String),
+    // The url and the proxy the request goes through
+    Http(String, ProxySettings),
+    AssumeOnline,
+}
+impl Probe {
+    pub fn from_settings(settings: &Settings) -> Self {
+        match settings.probe_mode {
+            ProbeMode::Tcp => Probe::Tcp(settings.check_address()),
+            ProbeMode::Http => Probe::Http(
+                settings.probe_url.trim().to_string(),
+                settings.proxy.clone(),
+            ),
+            ProbeMode::AssumeOnline => Probe::AssumeOnline,
+        }
+    }
+}
+
+fn is_connected(address: &str) -> bool {
+    // Resolving lets the probe be a hostname, a failed lookup counts as offline
+    let address = match address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+    {
+        Some(address) => address,
+        None => return false,
+    };
+    TcpStream::connect_timeout(&address, CHECK_TIMEOUT).is_ok()
+}
+
+// Goes through the global proxy like any other request
+async fn answers_head(url: &str, proxy: &ProxySettings) -> bool {
+    let client = match proxy
+        .apply_global(reqwest::Client::builder())
+        .and_then(|builder| {
+            builder
+                .timeout(CHECK_TIMEOUT)
+                .build()
+                .map_err(|e| e.to_string())
+        }) {
+        Ok(client) => client,
+        Err(_) => return false,
+    };
+    client.head(url).send().await.is_ok()
+}
+
+pub fn start_connectivity_check(app: &mut MyApp) {
+    if app.connected_to_net.started {
+        return;
+    }
+    app.connected_to_net.started = true;
+    let safe = app.connected_to_net.connected.clone();
+    let probe = app.connected_to_net.probe.clone();
+    let changed = app.connected_to_net.changed.clone();
+    let runtime = app.supervisor.handle();
+    let mut failures = 0;
+    std::thread::spawn(move || loop {
+        let answered = match probe.lock().clone() {
+            Probe::Tcp(address) => is_connected(&address),
+            // Saving the settings replaces the probe, its proxy included
+            Probe::Http(url, proxy) => runtime.block_on(answers_head(&url, &proxy)),
+            Probe::AssumeOnline => true,
+        };
+        failures = if answered { 0 } else { failures + 1 };
+        let mut current = safe.lock();
+        let connected = answered || (*current && failures < OFFLINE_AFTER);
+        if *current != connected {
+            *current = connected;
+            *changed.lock() = Some(now());
+        }
+        drop(current);
+        sleep(CHECK_INTERVAL);
+    });
+}
+
+// Pauses what was running when the connection drops and once it is back puts every download
+// back as it was, the running ones running and the queued ones in their place in the queue
+pub fn process_connectivity(app: &mut MyApp) {
+    let connected = *app.connected_to_net.connected.lock();
+    if connected == app.connected_to_net.applied {
+        return;
+    }
+    app.connected_to_net.applied = connected;
+    if connected {
+        let paused = std::mem::take(&mut app.connected_to_net.paused);
+        for core in app.inner.iter_mut() {
+            let key = file_key(&core.file);
+            let active = match paused.iter().find(|(paused, _)| *paused == key) {
+                Some((_, active)) => *active,
+                None => continue,
+            };
+            let done = core.file.complete.load(Ordering::Relaxed);
+            if done || *core.file.status.1.borrow() {
+                continue;
+            }
+            if active {
+                let _ = core.file.status.0.send(true);
+            } else {
+                core.queued = true;
+            }
+        }
+    } else if app.settings.pause_offline {
+        for core in app.inner.iter_mut() {
+            let active = is_active(core);
+            if core.queued || active {
+                app.connected_to_net
+                    .paused
+                    .push((file_key(&core.file), active));
+                core.queued = false;
+                let _ = core.file.status.0.send(false);
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{collections::BTreeMap, fs, sync::mpsc::Sender};
+
+use reqwest::RequestBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::ask_redownload,
+    config::{config_path, write_private_config_file},
+    errors::DownloadError,
+    mirrors::take_over,
+    queue::file_key,
+    retry::retry_download,
+    MyApp,
+};
+
+const CREDENTIALS_FILE: &str = "credentials.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Credential {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+impl Credential {
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credential::Basic { username, password } => {
+                request.basic_auth(username, Some(password))
+            }
+            Credential::Bearer { token } => request.bearer_auth(token),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Credential::Basic { .. } => "Basic",
+            Credential::Bearer { .. } => "Bearer",
+        }
+    }
+}
+
+// Keyed by host, with the port when the url names one
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Credentials {
+    pub hosts: BTreeMap<String, Credential>,
+}
+impl Credentials {
+    pub fn for_link(&self, link: &str) -> Option<Credential> {
+        host_of(link).and_then(|host| self.hosts.get(&host).cloned())
+    }
+}
+
+pub fn host_of(link: &str) -> Option<String> {
+    let url = reqwest::Url::parse(link.trim()).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host,
+    })
+}
+
+pub fn load_credentials() -> Credentials {
+    fs::read_to_string(config_path(CREDENTIALS_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Credentials>(&content).ok())
+        .unwrap_or_default()
+}
+
+// Stored as plain text, so at least nobody else on the machine gets to read them
+pub fn save_credentials(credentials: &Credentials) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(credentials).map_err(|e| e.to_string())?;
+    write_private_config_file(CREDENTIALS_FILE, &json)
+}
+
+// Opens the credentials window for a host that answered 401, the download is retried once they are saved
+pub fn request_credentials(app: &mut MyApp, host: String, retry: Option<String>) {
+    let form = &mut app.popus.credentials;
+    if form.show && form.prompt == host {
+        if retry.is_some() {
+            form.retry = retry;
+        }
+        return;
+    }
+    form.error = String::default();
+    form.host = host.clone();
+    form.bearer = false;
+    form.username = String::default();
+    form.password = String::default();
+    form.token = String::default();
+    match app.credentials.hosts.get(&host) {
+        Some(Credential::Basic { username, .. }) => form.username = username.clone(),
+        Some(Credential::Bearer { .. }) => form.bearer = true,
+        None => {}
+    }
+    form.prompt = host;
+    form.retry = retry;
+    form.show = true;
+}
+
+// Lets a background request open the credentials window when its host answers 401
+pub fn report_unauthorized(prompts: &Sender<String>, link: &str, error: &DownloadError) {
+    if error.is_unauthorized() {
+        if let Some(host) = host_of(link) {
+            let _ = prompts.send(host);
+        }
+    }
+}
+
+pub fn process_auth_prompts(app: &mut MyApp) {
+    while let Ok(host) = app.auth_channel.1.try_recv() {
+        request_credentials(app, host, None);
+    }
+}
+
+// Only the segment downloader sends credentials, so a download the engine started moves over to it
+pub fn retry_with_credentials(app: &mut MyApp, key: &str) {
+    let core = match app
+        .inner
+        .iter_mut()
+        .find(|core| file_key(&core.file) == key)
+    {
+        Some(core) => core,
+        None => return,
+    };
+    if !core.metadata.segmented() {
+        // Without a size there are no chunks to fetch, only adding it again helps
+        if core.file.url.total_size == 0 {
+            ask_redownload(&mut app.popus.confirm, key.to_string());
+            return;
+        }
+        core.metadata.authenticated = true;
+        if let Err(e) = take_over(&core.file, &mut core.metadata) {
+            app.popus.error.value = e;
+            app.popus.error.show = true;
+            return;
+        }
+    }
+    retry_download(core);
+}
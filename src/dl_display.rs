@@ -1,20 +1,43 @@
-use std::path::Path;
+use std::{path::Path, time::Instant};
 
-use dl::file2dl::Download;
 use eframe::egui::{
     Checkbox, Color32, Image, ImageButton, Label, ProgressBar, RichText, Rounding, Separator,
     TextWrapMode, Vec2,
 };
 use egui_extras::{Column, TableBuilder};
-use tokio::runtime::Runtime;
 
-use crate::{MyApp, Threading, ICON, PAUSE, RESUME};
+use crate::{
+    bandwidth::format_limit,
+    checksum::{ask_redownload, Verification},
+    errors::DownloadError,
+    extern_windows::open_timer,
+    history::{display_history, format_timestamp},
+    queue::{file_key, move_in_queue, queue_position, QueueMove},
+    resolve::cancel_resolve,
+    retry::retry_download,
+    MyApp, ICON,
+};
 
 pub fn display_interface(
     interface: &mut MyApp,
     ui: &mut eframe::egui::Ui,
     ctx: &eframe::egui::Context,
 ) {
+    ui.horizontal(|ui| {
+        ui.selectable_value(&mut interface.history.show, false, "Downloads");
+        ui.selectable_value(&mut interface.history.show, true, "History");
+    });
+    if interface.history.show {
+        display_history(interface, ui);
+        return;
+    }
+    let positions = (0..interface.inner.len())
+        .map(|index| queue_position(interface, index))
+        .collect::<Vec<Option<usize>>>();
+    let mut queue_move: Option<(usize, QueueMove)> = None;
+    let mut timer: Option<usize> = None;
+    let mut details: Option<usize> = None;
+    let mut cancel: Option<u64> = None;
     TableBuilder::new(ui)
         .striped(true)
         .resizable(false)
@@ -66,7 +89,7 @@ pub fn display_interface(
             });
         })
         .body(|mut body| {
-            for core in interface.inner.iter_mut() {
+            for (index, core) in interface.inner.iter_mut().enumerate() {
                 let status = *core.file.status.1.borrow();
                 let connected = *interface.connected_to_net.connected.lock();
                 let done = core
@@ -88,9 +111,32 @@ pub fn display_interface(
                                     .wrap_mode(TextWrapMode::Truncate);
                                 let res = ui.add(label);
                                 if res.hovered(){
-                                    let text = format!("Url: {}\n(Double click to open file)",core.file.url.link);
+                                    let text = format!("Url: {}\n(Double click to open file, right click for more)",core.file.url.link);
                                     res.show_tooltip_text(text);
                                 };
+                                res.context_menu(|ui| {
+                                    if ui.button("Move to top").clicked() {
+                                        queue_move = Some((index, QueueMove::Top));
+                                        ui.close_menu();
+                                    }
+                                    if ui.button("Move up").clicked() {
+                                        queue_move = Some((index, QueueMove::Up));
+                                        ui.close_menu();
+                                    }
+                                    if ui.button("Move down").clicked() {
+                                        queue_move = Some((index, QueueMove::Down));
+                                        ui.close_menu();
+                                    }
+                                    ui.separator();
+                                    if ui.button("Schedule start/stop").clicked() {
+                                        timer = Some(index);
+                                        ui.close_menu();
+                                    }
+                                    if ui.button("Details").clicked() {
+                                        details = Some(index);
+                                        ui.close_menu();
+                                    }
+                                });
                                 if res.double_clicked(){
                                     let path = format!("{}/{}",core.file.dir,core.file.name_on_disk);
                                     match opener::open(path){
@@ -165,67 +211,91 @@ pub fn display_interface(
                         });
                     });
                     row.col(|ui| {
-                        if status && !core.started {
-                            let rt = Runtime::new().unwrap();
-                            let mut file_clone = core.file.clone();
-                            let tx = core.channel.0.clone();
-                            let threads = core.threads;
-                            if core.threading.clone() == Threading::Single && Path::new(&core.file.dir).join(&core.file.name_on_disk).is_file(){
-                                std::thread::spawn(move ||{
-                                    rt.block_on(async move {
-                                        loop{
-                                            match file_clone.single_thread_dl().await {
-                                                Ok(_) => break,
-                                                Err(e) => {
-                                                    let error = format!("{:?}",e);
-                                                    if !error.contains("ConnectError"){
-                                                        tx.send(error).unwrap()
-                                                    }
-                                                },
-                                            }
-                                        }
-                                    });
-                                });
-                            }
-                            else {
-                                std::thread::spawn(move ||{
-                                    rt.block_on(async move {
-                                        loop{
-                                            match file_clone.multi_thread_dl(threads).await {
-                                                Ok(_) => break,
-                                                Err(e) => {
-                                                    let error = format!("{:?}",e);
-                                                    if !error.contains("ConnectError"){
-                                                        tx.send(error).unwrap()
-                                                    }
-                                                },
-                                            }
-                                        }
-                                    });
-                                });
+                        if let Some(error) = core.error.clone() {
+                            let res = ui.colored_label(Color32::RED, error.summary());
+                            if res.hovered() {
+                                res.show_tooltip_text(format!("{}\n(Right click to retry or dismiss)", error.details()));
                             }
-                            core.started = true;
+                            res.context_menu(|ui| {
+                                if ui.button("Retry").clicked() {
+                                    if let DownloadError::Checksum { .. } = error {
+                                        ask_redownload(&mut interface.popus.confirm, file_key(&core.file));
+                                    } else {
+                                        retry_download(core);
+                                    }
+                                    ui.close_menu();
+                                }
+                                if ui.button("Dismiss").clicked() {
+                                    core.error = None;
+                                    ui.close_menu();
+                                }
+                            });
                         }
-                        if !connected{
+                        else if !connected{
                             ui.colored_label(Color32::RED, "Disconnected");
                         }
+                        else if let Some(start_at) = core.metadata.start_at.filter(|_| !status && !done) {
+                            let res = ui.colored_label(Color32::LIGHT_BLUE, format!("Starts at {}", format_timestamp(Some(start_at))));
+                            if res.hovered() {
+                                res.show_tooltip_text("(Right click the filename to change)");
+                            }
+                        }
+                        else if let Some(position) = positions[index] {
+                            ui.colored_label(Color32::LIGHT_BLUE, format!("Queued #{}", position));
+                        }
                         else if !done && status {
-                            if let Ok(e) = core.channel.1.try_recv() {
-                                println!("{e}");
-                                ui.colored_label(Color32::RED, e);
+                            if let Some(retry) = &core.retry {
+                                let seconds = retry.until.saturating_duration_since(Instant::now()).as_secs() + 1;
+                                let res = ui.colored_label(Color32::ORANGE, format!("Retrying in {}s (attempt {}/{})", seconds, retry.attempt, retry.max_attempts));
+                                if res.hovered() {
+                                    res.show_tooltip_text(retry.error.to_string());
+                                }
+                            } else if let Some(stop_at) = core.metadata.stop_at {
+                                ui.colored_label(Color32::GREEN, format!("Downloading until {}", format_timestamp(Some(stop_at))));
                             } else {
                                 ui.colored_label(Color32::GREEN, "Downloading");
                             }
                         } else if !done && !status {
                             ui.colored_label(Color32::YELLOW, "Paused");
                         } else {
-                            ui.colored_label(Color32::DARK_GREEN, "Complete");
+                            let expected = core.metadata.checksum.as_ref().map(|checksum| checksum.to_string()).unwrap_or_default();
+                            match &core.verification {
+                                Verification::Verified => {
+                                    let res = ui.colored_label(Color32::DARK_GREEN, "Verified");
+                                    if res.hovered() {
+                                        res.show_tooltip_text(format!("Checksum matches\n{}", expected));
+                                    }
+                                }
+                                Verification::Verifying => {
+                                    ui.colored_label(Color32::YELLOW, "Verifying...");
+                                }
+                                Verification::Mismatch(actual) => {
+                                    let res = ui.colored_label(Color32::RED, "Checksum mismatch");
+                                    if res.hovered() {
+                                        res.show_tooltip_text(format!("Expected: {}\nActual: {}\n(Double click to download again)", expected, actual));
+                                    }
+                                    if res.double_clicked() {
+                                        ask_redownload(&mut interface.popus.confirm, file_key(&core.file));
+                                    }
+                                }
+                                Verification::Failed(e) => {
+                                    let res = ui.colored_label(Color32::RED, "Verification failed");
+                                    if res.hovered() {
+                                        res.show_tooltip_text(e);
+                                    }
+                                }
+                                Verification::Unverified if core.discovery.is_some() => {
+                                    ui.colored_label(Color32::YELLOW, "Looking for checksum...");
+                                }
+                                Verification::Unverified => {
+                                    ui.colored_label(Color32::DARK_GREEN, "Complete");
+                                }
+                            }
                         }
                     });
                     row.col(|ui| {
-                        let bandwidth = core.file
-                        .bandwidth_chosen
-                        .load(std::sync::atomic::Ordering::Relaxed);
+                        let bandwidth = core.limit;
+                        let share = core.file.bandwidth_chosen.load(std::sync::atomic::Ordering::Relaxed);
                         let text = if bandwidth == 0 {
                             "Unlimited".to_string()
                         } else if bandwidth >= 500_000_000 {
@@ -237,7 +307,12 @@ pub fn display_interface(
                         };
                         let res = ui.label(text);
                         if res.hovered() {
-                            res.show_tooltip_text("Bandwidth limiter\n(Click twice to change)");
+                            let text = if share != bandwidth {
+                                format!("Bandwidth limiter\nGlobal limit share: {}\n(Click twice to change)", format_limit(share))
+                            } else {
+                                "Bandwidth limiter\n(Click twice to change)".to_string()
+                            };
+                            res.show_tooltip_text(text);
                         }
                         if res.double_clicked() {
                             interface.popus.bandwidth.show = true;
@@ -285,7 +360,12 @@ pub fn display_interface(
                         ui.vertical_centered(|ui|{
                             if !done {
                                 if ui.add(img_butt.clone()).clicked(){
-                                    core.file.switch_status().unwrap();
+                                    if status {
+                                        core.file.switch_status().unwrap();
+                                    } else {
+                                        // Resuming goes through the queue so the active limit is respected
+                                        core.queued = !core.queued;
+                                    }
                                 }
                             } else if Path::new(&name).is_dir() && supposed_path.exists() {
                                 let res = ui.add(img_butt);
@@ -303,5 +383,48 @@ pub fn display_interface(
                     });
                 });
             }
+            // Added downloads whose metadata is still on its way
+            for pending in interface.resolver.rows.iter() {
+                body.row(25.0, |mut row| {
+                    row.col(|ui| {
+                        ui.add_enabled(false, Checkbox::without_text(&mut false));
+                    });
+                    row.col(|ui| {
+                        let res = ui.add(Label::new(&pending.label).wrap_mode(TextWrapMode::Truncate));
+                        if res.hovered() {
+                            res.show_tooltip_text(format!("Url: {}\n(Right click to cancel)", pending.label));
+                        }
+                        res.context_menu(|ui| {
+                            if ui.button("Cancel").clicked() {
+                                cancel = Some(pending.id);
+                                ui.close_menu();
+                            }
+                        });
+                    });
+                    row.col(|ui| {
+                        ui.spinner();
+                    });
+                    row.col(|ui| {
+                        ui.colored_label(Color32::YELLOW, format!("Resolving... ({}s)", pending.started.elapsed().as_secs()));
+                    });
+                    row.col(|_| {});
+                    row.col(|_| {});
+                    row.col(|_| {});
+                    row.col(|_| {});
+                });
+            }
         });
+    if let Some(id) = cancel {
+        cancel_resolve(interface, id);
+    }
+    if let Some((index, direction)) = queue_move {
+        move_in_queue(interface, index, direction);
+    }
+    if let Some(index) = timer {
+        open_timer(interface, index);
+    }
+    if let Some(index) = details {
+        interface.popus.details.key = file_key(&interface.inner[index].file);
+        interface.popus.details.show = true;
+    }
 }
@@ -0,0 +1,96 @@
+use std::error::Error;
+
+use crate::retry::{classify, FailureKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadError {
+    Network(String),
+    Timeout(String),
+    HttpStatus { status: u16, details: String },
+    Disk(String),
+    Permission(String),
+    Checksum { expected: String, actual: String },
+    RangesUnsupported,
+    Other(String),
+}
+
+impl DownloadError {
+    // Keeps the message next to what retry::classify made of the error
+    pub fn from_error(error: &(dyn Error + 'static)) -> Self {
+        let details = error.to_string();
+        match classify(error) {
+            FailureKind::Network => DownloadError::Network(details),
+            FailureKind::Timeout => DownloadError::Timeout(details),
+            FailureKind::HttpStatus(status) => DownloadError::HttpStatus { status, details },
+            FailureKind::Disk => DownloadError::Disk(details),
+            FailureKind::Permission => DownloadError::Permission(details),
+            FailureKind::Other => DownloadError::Other(details),
+        }
+    }
+
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            DownloadError::Network(_) => FailureKind::Network,
+            DownloadError::Timeout(_) => FailureKind::Timeout,
+            DownloadError::HttpStatus { status, .. } => FailureKind::HttpStatus(*status),
+            DownloadError::Disk(_) => FailureKind::Disk,
+            DownloadError::Permission(_) => FailureKind::Permission,
+            DownloadError::Checksum { .. }
+            | DownloadError::RangesUnsupported
+            | DownloadError::Other(_) => FailureKind::Other,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, DownloadError::HttpStatus { status: 401, .. })
+    }
+
+    pub fn is_network(&self) -> bool {
+        match self {
+            DownloadError::Network(_) | DownloadError::Timeout(_) => true,
+            DownloadError::HttpStatus { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    // Short enough for the Status column
+    pub fn summary(&self) -> String {
+        match self {
+            DownloadError::Network(_) => "Network error".to_string(),
+            DownloadError::Timeout(_) => "Timed out".to_string(),
+            DownloadError::HttpStatus { status, .. } => format!("HTTP {}", status),
+            DownloadError::Disk(_) => "Disk error".to_string(),
+            DownloadError::Permission(_) => "Permission denied".to_string(),
+            DownloadError::Checksum { .. } => "Checksum mismatch".to_string(),
+            DownloadError::RangesUnsupported => "Cannot resume".to_string(),
+            DownloadError::Other(_) => "Error".to_string(),
+        }
+    }
+
+    pub fn details(&self) -> String {
+        match self {
+            DownloadError::Network(details)
+            | DownloadError::Timeout(details)
+            | DownloadError::Disk(details)
+            | DownloadError::Permission(details)
+            | DownloadError::Other(details) => details.clone(),
+            DownloadError::HttpStatus { details, .. } => details.clone(),
+            DownloadError::Checksum { expected, actual } => {
+                format!("Expected: {}\nActual: {}", expected, actual)
+            }
+            DownloadError::RangesUnsupported => {
+                "The server does not support ranges, so the download cannot be resumed".to_string()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.summary(), self.details())
+    }
+}
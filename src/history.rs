@@ -0,0 +1,443 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{atomic::Ordering, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eframe::egui::{Color32, Label, Separator, TextEdit, TextWrapMode};
+use egui_extras::{Column, TableBuilder};
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::{
+    config::{config_path, write_config_file},
+    queue::file_key,
+    MyApp,
+};
+
+const HISTORY_FILE: &str = "history.json";
+
+static LOCAL_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    InProgress,
+    Completed,
+    Failed(String),
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    key: String,
+    pub url: String,
+    pub path: String,
+    pub size: usize,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    // Bytes already on disk when the session started, resumed data does not count towards the speed
+    resumed_from: usize,
+    pub average_speed: usize,
+    pub outcome: Outcome,
+}
+
+#[derive(Default)]
+pub struct History {
+    pub entries: Vec<HistoryEntry>,
+    pub show: bool,
+    pub search: String,
+    dirty: bool,
+    // What the download list looked like when the entries were last brought up to date
+    seen: Option<u64>,
+}
+
+impl History {
+    // Forgets what was seen so the next pass saves the change, even with an unchanged download list
+    fn changed(&mut self) {
+        self.dirty = true;
+        self.seen = None;
+    }
+
+    // Downloads still running keep their entries
+    pub fn clear(&mut self) {
+        self.entries
+            .retain(|entry| entry.outcome == Outcome::InProgress);
+        self.changed();
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < self.entries.len() {
+            self.entries.remove(index);
+            self.changed();
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+// The offset can only be read safely while the process is single threaded, so main reads it first
+pub fn local_offset() -> UtcOffset {
+    *LOCAL_OFFSET.get_or_init(|| UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC))
+}
+
+pub fn format_timestamp(timestamp: Option<u64>) -> String {
+    let time = match timestamp
+        .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp as i64).ok())
+    {
+        Some(time) => time.to_offset(local_offset()),
+        None => return "-".to_string(),
+    };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+pub fn domain_of(link: &str) -> String {
+    reqwest::Url::parse(link)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_default()
+}
+
+pub fn load_history() -> History {
+    let entries = fs::read_to_string(config_path(HISTORY_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Vec<HistoryEntry>>(&content).ok())
+        .unwrap_or_default();
+    History {
+        entries,
+        ..Default::default()
+    }
+}
+
+fn save_history(history: &History) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(&history.entries).map_err(|e| e.to_string())?;
+    write_config_file(HISTORY_FILE, &json)
+}
+
+fn finish(entry: &mut HistoryEntry, downloaded: usize, outcome: Outcome) {
+    let finished = now();
+    let elapsed = entry
+        .started
+        .map(|started| finished.saturating_sub(started))
+        .unwrap_or_default();
+    if elapsed > 0 {
+        entry.average_speed = downloaded.saturating_sub(entry.resumed_from) / elapsed as usize;
+    }
+    entry.finished = Some(finished);
+    entry.outcome = outcome;
+}
+
+// Covers everything process_history reacts to, without allocating every frame
+fn fingerprint(app: &MyApp) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for core in app.inner.iter() {
+        core.file.dir.hash(&mut hasher);
+        core.file.name_on_disk.hash(&mut hasher);
+        core.file.complete.load(Ordering::Relaxed).hash(&mut hasher);
+        core.started.hash(&mut hasher);
+        core.error.is_some().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// Opens an entry when a download task starts and closes it once the download completes, fails or leaves the list
+pub fn process_history(app: &mut MyApp) {
+    let seen = fingerprint(app);
+    if app.history.seen == Some(seen) {
+        return;
+    }
+    app.history.seen = Some(seen);
+    let history = &mut app.history;
+    let mut latest = HashMap::new();
+    for (index, entry) in history.entries.iter().enumerate() {
+        latest.insert(entry.key.clone(), index);
+    }
+    let mut present = HashSet::new();
+    for core in app.inner.iter() {
+        let key = file_key(&core.file);
+        let complete = core.file.complete.load(Ordering::Relaxed);
+        let downloaded = core.file.size_on_disk.load(Ordering::Relaxed);
+        let open = latest
+            .get(&key)
+            .filter(|index| history.entries[**index].outcome == Outcome::InProgress)
+            .copied();
+        match open {
+            Some(index) if complete => {
+                finish(&mut history.entries[index], downloaded, Outcome::Completed);
+                history.dirty = true;
+            }
+            Some(index) => {
+                if let Some(error) = &core.error {
+                    finish(
+                        &mut history.entries[index],
+                        downloaded,
+                        Outcome::Failed(error.to_string()),
+                    );
+                    history.dirty = true;
+                }
+            }
+            // Files that finished before the history existed still deserve an entry
+            None if (complete && !latest.contains_key(&key)) || (core.started && !complete) => {
+                history.entries.push(HistoryEntry {
+                    key: key.clone(),
+                    url: core.file.url.link.clone(),
+                    path: key.clone(),
+                    size: core.file.url.total_size,
+                    started: (!complete).then(now),
+                    finished: None,
+                    resumed_from: downloaded,
+                    average_speed: 0,
+                    outcome: if complete {
+                        Outcome::Completed
+                    } else {
+                        Outcome::InProgress
+                    },
+                });
+                latest.insert(key.clone(), history.entries.len() - 1);
+                history.dirty = true;
+            }
+            None => {}
+        }
+        present.insert(key);
+    }
+    for entry in history.entries.iter_mut() {
+        if entry.outcome == Outcome::InProgress && !present.contains(&entry.key) {
+            finish(entry, 0, Outcome::Removed);
+            entry.average_speed = 0;
+            history.dirty = true;
+        }
+    }
+    if history.dirty {
+        history.dirty = false;
+        if let Err(e) = save_history(history) {
+            app.popus.error.value = e;
+            app.popus.error.show = true;
+        }
+    }
+}
+
+fn matches(entry: &HistoryEntry, search: &str) -> bool {
+    let search = search.trim().to_lowercase();
+    if search.is_empty() {
+        return true;
+    }
+    let name = Path::new(&entry.path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    name.contains(&search)
+        || entry.url.to_lowercase().contains(&search)
+        || domain_of(&entry.url).to_lowercase().contains(&search)
+}
+
+fn format_speed(speed: usize) -> String {
+    if speed == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.2}MB/s", speed as f64 / 1024.0 / 1024.0)
+    }
+}
+
+pub fn display_history(app: &mut MyApp, ui: &mut eframe::egui::Ui) {
+    let mut redownload: Option<(String, String)> = None;
+    let mut remove: Option<usize> = None;
+    ui.horizontal(|ui| {
+        ui.label("Search");
+        ui.add(
+            TextEdit::singleline(&mut app.history.search)
+                .hint_text("Filename, URL or domain")
+                .desired_width(300.0),
+        );
+        if ui.button("Clear history").clicked() {
+            app.history.clear();
+        }
+    });
+    let rows = app
+        .history
+        .entries
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, entry)| matches(entry, &app.history.search))
+        .map(|(index, _)| index)
+        .collect::<Vec<usize>>();
+    TableBuilder::new(ui)
+        .striped(true)
+        .resizable(false)
+        .auto_shrink(true)
+        .scroll_bar_visibility(eframe::egui::scroll_area::ScrollBarVisibility::AlwaysVisible)
+        .column(Column::auto().resizable(true).at_least(200.0))
+        .column(Column::auto().resizable(true).at_least(120.0))
+        .column(Column::auto().resizable(true).at_least(80.0))
+        .column(Column::auto().resizable(true).at_least(120.0))
+        .column(Column::auto().resizable(true).at_least(120.0))
+        .column(Column::auto().resizable(true).at_least(90.0))
+        .column(Column::auto().resizable(true).at_least(90.0))
+        .column(Column::remainder().resizable(false))
+        .header(20.0, |mut header| {
+            for title in [
+                "Filename",
+                "Domain",
+                "Size",
+                "Started",
+                "Finished",
+                "Average speed",
+                "Outcome",
+                "",
+            ] {
+                header.col(|ui| {
+                    ui.heading(title);
+                    ui.add(Separator::grow(Separator::default(), ui.available_width()));
+                });
+            }
+        })
+        .body(|mut body| {
+            for index in rows {
+                let entry = &app.history.entries[index];
+                body.row(25.0, |mut row| {
+                    row.col(|ui| {
+                        let name = Path::new(&entry.path)
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        let res = ui.add(Label::new(name).wrap_mode(TextWrapMode::Truncate));
+                        if res.hovered() {
+                            res.show_tooltip_text(format!(
+                                "Url: {}\nPath: {}\n(Right click for more)",
+                                entry.url, entry.path
+                            ));
+                        }
+                        res.context_menu(|ui| {
+                            if ui.button("Copy URL").clicked() {
+                                ui.output_mut(|output| output.copied_text = entry.url.clone());
+                                ui.close_menu();
+                            }
+                            if ui.button("Remove from history").clicked() {
+                                remove = Some(index);
+                                ui.close_menu();
+                            }
+                        });
+                    });
+                    row.col(|ui| {
+                        ui.label(domain_of(&entry.url));
+                    });
+                    row.col(|ui| {
+                        ui.label(format!("{:.3}MB", entry.size as f64 / 1024.0 / 1024.0));
+                    });
+                    row.col(|ui| {
+                        ui.label(format_timestamp(entry.started));
+                    });
+                    row.col(|ui| {
+                        ui.label(format_timestamp(entry.finished));
+                    });
+                    row.col(|ui| {
+                        ui.label(format_speed(entry.average_speed));
+                    });
+                    row.col(|ui| match &entry.outcome {
+                        Outcome::InProgress => {
+                            ui.colored_label(Color32::LIGHT_BLUE, "In progress");
+                        }
+                        Outcome::Completed => {
+                            ui.colored_label(Color32::DARK_GREEN, "Completed");
+                        }
+                        Outcome::Failed(e) => {
+                            let res = ui.colored_label(Color32::RED, "Failed");
+                            if res.hovered() {
+                                res.show_tooltip_text(e);
+                            }
+                        }
+                        Outcome::Removed => {
+                            ui.colored_label(Color32::YELLOW, "Removed");
+                        }
+                    });
+                    row.col(|ui| {
+                        if ui.button("Re-download").clicked() {
+                            let dir = Path::new(&entry.path)
+                                .parent()
+                                .map(|dir| dir.to_string_lossy().to_string())
+                                .unwrap_or_default();
+                            redownload = Some((entry.url.clone(), dir));
+                        }
+                    });
+                });
+            }
+        });
+    if let Some(index) = remove {
+        app.history.remove(index);
+    }
+    if let Some((url, dir)) = redownload {
+        let download = &mut app.popus.download;
+        download.url = url;
+        download.save_to = dir;
+        download.error = String::default();
+        download.show = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, outcome: Outcome) -> HistoryEntry {
+        HistoryEntry {
+            key: key.to_string(),
+            url: format!("https://example.com/{}", key),
+            path: key.to_string(),
+            size: 0,
+            started: None,
+            finished: None,
+            resumed_from: 0,
+            average_speed: 0,
+            outcome,
+        }
+    }
+
+    fn history() -> History {
+        History {
+            entries: vec![
+                entry("done", Outcome::Completed),
+                entry("running", Outcome::InProgress),
+                entry("failed", Outcome::Failed("404".to_string())),
+            ],
+            seen: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn clearing_keeps_running_entries_and_is_saved_on_the_next_pass() {
+        let mut history = history();
+        history.clear();
+        assert_eq!(history.entries, vec![entry("running", Outcome::InProgress)]);
+        assert!(history.dirty);
+        assert_eq!(history.seen, None);
+    }
+
+    #[test]
+    fn removing_an_entry_is_saved_on_the_next_pass() {
+        let mut history = history();
+        history.remove(0);
+        assert_eq!(history.entries.len(), 2);
+        assert!(history.dirty);
+        assert_eq!(history.seen, None);
+        history.seen = Some(1);
+        history.dirty = false;
+        history.remove(5);
+        assert!(!history.dirty);
+        assert_eq!(history.seen, Some(1));
+    }
+}
@@ -1,23 +1,69 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+use bandwidth::{process_bandwidth, TokenBucket};
+use checksum::{process_verification, verification_of, Checksum, Verification};
+use checksum_discovery::process_discovery;
+use config::{load_downloads, load_settings, Settings};
+use connectivity::{process_connectivity, start_connectivity_check, Probe, ProbeMode};
+use credentials::{load_credentials, process_auth_prompts, Credentials};
 use dl::{file2dl::File2Dl, utils::count_files};
 use dl_display::display_interface;
 use eframe::egui::{self, mutex::Mutex, Color32, Separator};
+use errors::DownloadError;
 use extern_windows::{
-    show_bandwidth_edit_window, show_confirm_window, show_error_window, show_input_window,
+    show_bandwidth_edit_window, show_batch_window, show_confirm_window, show_credentials_window,
+    show_details_window, show_error_window, show_input_window, show_schedule_window,
+    show_settings_window, show_timer_window,
 };
+use history::{load_history, process_history, History};
 use menu_bar::init_menu_bar;
+use metadata::{load_metadata, Metadata};
+use metalink::process_dropped_files;
+use mirrors::{done_bytes, mirror_part_path, MirrorStat};
+use proxy::ProxyChoice;
+use queue::{load_queue, process_queue, process_running_state, resume_on_startup, Queue};
+use resolve::{process_resolved, Resolved, Resolver};
+use retry::{process_download_events, DownloadEvent, RetryPolicy, RetryStatus};
+use rpc::{handle_rpc_calls, sync_rpc_server, RpcServer};
+use schedule::{
+    load_schedule, process_download_timers, process_schedule, start_schedule_timer, RuleForm,
+    Scheduler,
+};
 use select::select_all;
 use status_bar::display_status_bar;
 use std::{
     path::Path,
     sync::{mpsc, Arc},
 };
+use supervisor::{supervise, Supervisor};
+mod aria2;
+mod bandwidth;
+mod batch;
+mod checksum;
+mod checksum_discovery;
+mod cli;
+mod config;
+mod connectivity;
+mod credentials;
 mod dl_display;
+mod errors;
 mod extern_windows;
+mod history;
 mod menu_bar;
+mod metadata;
+mod metalink;
+mod mirrors;
+mod pattern;
+mod proxy;
+mod queue;
+mod request;
+mod resolve;
+mod retry;
+mod rpc;
+mod schedule;
 mod select;
 mod status_bar;
+mod supervisor;
 
 pub const ICON: &[u8] = include_bytes!("../icon.png");
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
@@ -26,18 +72,39 @@ pub enum Threading {
     Single,
     Multi,
 }
+impl Threading {
+    // More than one thread only makes sense split into parts
+    pub fn for_threads(threads: usize) -> Self {
+        if threads > 1 {
+            Threading::Multi
+        } else {
+            Threading::Single
+        }
+    }
+}
 
 struct DownloadInterface {
     error: String,
     url: String,
     bandwidth: String,
+    save_to: String,
+    start_at: String,
+    stop_at: String,
+    checksum: String,
+    discover_checksum: bool,
+    // Advanced section, headers one "Name: value" per line
+    headers: String,
+    cookies: String,
+    user_agent: String,
+    proxy: ProxyChoice,
     show: bool,
     threading: Threading,
     threads: String,
-    error_channel: (
-        std::sync::mpsc::Sender<String>,
-        std::sync::mpsc::Receiver<String>,
-    ),
+    // Expansion of a url pattern, kept until the url changes
+    preview_source: String,
+    preview: Result<Vec<String>, String>,
+    // The resolving row the dialog waits for
+    waiting: Option<u64>,
 }
 impl Default for DownloadInterface {
     fn default() -> Self {
@@ -45,25 +112,132 @@ impl Default for DownloadInterface {
             error: String::default(),
             url: String::default(),
             bandwidth: String::default(),
+            save_to: String::default(),
+            start_at: String::default(),
+            stop_at: String::default(),
+            checksum: String::default(),
+            discover_checksum: false,
+            headers: String::default(),
+            cookies: String::default(),
+            user_agent: String::default(),
+            proxy: ProxyChoice::default(),
             threading: Threading::default(),
             threads: String::default(),
+            preview_source: String::default(),
+            preview: Ok(Vec::new()),
+            waiting: None,
             show: false,
-            error_channel: std::sync::mpsc::channel(),
         }
     }
 }
+impl DownloadInterface {
+    // Closes the dialog after a download was added, the threading choices stay for the next one
+    fn reset(&mut self) {
+        self.show = false;
+        self.error = String::default();
+        self.url = String::default();
+        self.checksum = String::default();
+        self.save_to = String::default();
+        self.start_at = String::default();
+        self.stop_at = String::default();
+        self.headers = String::default();
+        self.cookies = String::default();
+        self.user_agent = String::default();
+        self.proxy = ProxyChoice::default();
+    }
+}
+#[derive(Default)]
+struct BatchInterface {
+    error: String,
+    show: bool,
+    text: String,
+    save_to: String,
+    bandwidth: String,
+    threading: Threading,
+    threads: String,
+    // Lines still resolving, and what failed so far
+    waiting: Vec<u64>,
+    total: usize,
+    failures: Vec<String>,
+    failed_lines: Vec<String>,
+}
 #[derive(Default)]
 struct ErrorInterface {
     value: String,
     show: bool,
 }
 
+#[derive(Default)]
+struct SettingsInterface {
+    error: String,
+    show: bool,
+    download_dir: String,
+    probe_host: String,
+    probe_port: String,
+    default_threads: String,
+    default_bandwidth: String,
+    global_bandwidth: String,
+    max_attempts: String,
+    probe_mode: ProbeMode,
+    probe_url: String,
+    pause_offline: bool,
+    resume_on_startup: bool,
+    proxy_url: String,
+    proxy_username: String,
+    proxy_password: String,
+    no_proxy: String,
+}
+
+#[derive(Default)]
+struct ScheduleInterface {
+    error: String,
+    show: bool,
+    enabled: bool,
+    rules: Vec<RuleForm>,
+}
+
+#[derive(Default)]
+struct TimerInterface {
+    error: String,
+    show: bool,
+    key: String,
+    start_at: String,
+    stop_at: String,
+}
+
+#[derive(Default)]
+struct DetailsInterface {
+    show: bool,
+    key: String,
+}
+
+#[derive(Default)]
+struct CredentialsInterface {
+    error: String,
+    show: bool,
+    // Host that answered 401, empty when opened from the menu
+    prompt: String,
+    host: String,
+    bearer: bool,
+    username: String,
+    password: String,
+    token: String,
+    // Download retried once credentials for its host are saved
+    retry: Option<String>,
+}
+
 #[derive(Default)]
 struct PopUps {
     error: ErrorInterface,
     confirm: ConfirmInterface,
     download: DownloadInterface,
+    batch: BatchInterface,
     bandwidth: BandwidthInterface,
+    settings: SettingsInterface,
+    schedule: ScheduleInterface,
+    timer: TimerInterface,
+    details: DetailsInterface,
+    credentials: CredentialsInterface,
 }
 #[derive(Debug, Default, PartialEq, Eq)]
 enum BandwidthUnit {
@@ -100,22 +274,96 @@ struct Core {
     file: File2Dl,
     started: bool,
     selected: bool,
+    queued: bool,
     channel: (
-        std::sync::mpsc::Sender<String>,
-        std::sync::mpsc::Receiver<String>,
+        std::sync::mpsc::Sender<DownloadEvent>,
+        std::sync::mpsc::Receiver<DownloadEvent>,
     ),
+    retry: Option<RetryStatus>,
+    error: Option<DownloadError>,
+    // Per file limit chosen by the user, the engine may get less while the global limit applies
+    limit: usize,
     threading: Threading,
     threads: usize,
+    metadata: Metadata,
+    verification: Verification,
+    verification_channel: (
+        std::sync::mpsc::Sender<Verification>,
+        std::sync::mpsc::Receiver<Verification>,
+    ),
+    discovery: Option<std::sync::mpsc::Receiver<Option<Checksum>>>,
+    mirror_stats: Arc<Mutex<Vec<MirrorStat>>>,
+}
+impl Core {
+    fn new(mut file: File2Dl, threading: Threading, threads: usize) -> Self {
+        let metadata = load_metadata(&file);
+        if let Some(total_size) = metadata.total_size {
+            file.url.total_size = total_size;
+        }
+        let verification = match (&metadata.checksum, &metadata.computed_digest) {
+            (Some(checksum), Some(computed)) => verification_of(checksum, computed),
+            _ => Verification::default(),
+        };
+        // The engine knows nothing about chunks fetched by the segment downloader
+        if metadata.segmented() && !file.complete.load(std::sync::atomic::Ordering::Relaxed) {
+            let done = done_bytes(&metadata.mirror_chunks, file.url.total_size);
+            file.size_on_disk
+                .store(done, std::sync::atomic::Ordering::Relaxed);
+            // Its size may not match what the engine expected, the renamed file is what counts
+            if done == file.url.total_size
+                && !mirror_part_path(&file).exists()
+                && std::path::Path::new(&file.dir)
+                    .join(&file.name_on_disk)
+                    .is_file()
+            {
+                file.complete
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        let limit = metadata.bandwidth_limit.unwrap_or(
+            file.bandwidth_chosen
+                .load(std::sync::atomic::Ordering::Relaxed),
+        );
+        Self {
+            file,
+            started: false,
+            selected: false,
+            queued: false,
+            channel: mpsc::channel(),
+            retry: None,
+            error: None,
+            limit,
+            threading,
+            threads,
+            metadata,
+            verification,
+            verification_channel: mpsc::channel(),
+            discovery: None,
+            mirror_stats: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
 }
 struct Connected {
     connected: Arc<Mutex<bool>>,
+    // What the background check does, updated when the settings change
+    probe: Arc<Mutex<Probe>>,
+    // Unix time the state last flipped
+    changed: Arc<Mutex<Option<u64>>>,
     started: bool,
+    // State the downloads were last paused or resumed for, and the ones paused with whether they were running
+    applied: bool,
+    paused: Vec<(String, bool)>,
 }
 impl Default for Connected {
     fn default() -> Self {
         Self {
             connected: Arc::new(Mutex::new(false)),
+            probe: Arc::new(Mutex::new(Probe::from_settings(&Settings::default()))),
+            changed: Arc::new(Mutex::new(None)),
             started: false,
+            // Nothing is known until the first check, an app started offline still pauses its queue
+            applied: true,
+            paused: Vec::new(),
         }
     }
 }
@@ -125,64 +373,85 @@ struct MyApp {
     popus: PopUps,
     select_all: bool,
     connected_to_net: Connected,
+    queue: Queue,
+    retry_policy: RetryPolicy,
+    // Refilled at the rate process_bandwidth leaves for the segmented downloads
+    bucket: Arc<TokenBucket>,
+    rpc: RpcServer,
+    history: History,
+    settings: Settings,
+    scheduler: Scheduler,
+    resolver: Resolver,
+    supervisor: Supervisor,
+    credentials: Credentials,
     file_channel: (
-        std::sync::mpsc::Sender<File2Dl>,
-        std::sync::mpsc::Receiver<File2Dl>,
+        std::sync::mpsc::Sender<Resolved>,
+        std::sync::mpsc::Receiver<Resolved>,
+    ),
+    // Hosts that answered 401 while resolving
+    auth_channel: (
+        std::sync::mpsc::Sender<String>,
+        std::sync::mpsc::Receiver<String>,
     ),
 }
 
 impl Default for MyApp {
     fn default() -> Self {
-        let collection = match File2Dl::from("Downloads") {
-            Ok(collection) => collection,
-            Err(e) => {
-                let popus = PopUps {
-                    error: ErrorInterface {
-                        value: e.to_string(),
-                        show: true,
-                    },
-                    confirm: ConfirmInterface::default(),
-                    download: DownloadInterface::default(),
-                    bandwidth: BandwidthInterface::default(),
-                };
-                return Self {
-                    inner: Vec::default(),
-                    popus,
-                    connected_to_net: Connected::default(),
-                    select_all: false,
-                    file_channel: std::sync::mpsc::channel(),
-                };
-            }
-        };
-        let core_collection = collection
+        let settings = load_settings();
+        let (collection, errors) = load_downloads(&settings);
+        let mut core_collection = collection
             .iter()
-            .map(|file| Core {
-                file: file.to_owned(),
-                started: false,
-                selected: false,
-                threading: {
-                    if Path::new(&file.dir).join(&file.name_on_disk).is_dir() {
-                        Threading::Multi
-                    } else {
-                        Threading::Single
-                    }
-                },
-                threads: count_files(&format!("{}/.{}", file.dir, file.name_on_disk))
-                    .unwrap_or_default(),
-                channel: mpsc::channel(),
+            .map(|file| {
+                let threading = if Path::new(&file.dir).join(&file.name_on_disk).is_dir() {
+                    Threading::Multi
+                } else {
+                    Threading::Single
+                };
+                let threads = count_files(&format!("{}/.{}", file.dir, file.name_on_disk))
+                    .unwrap_or_default();
+                Core::new(file.to_owned(), threading, threads)
             })
             .collect::<Vec<Core>>();
+        let queue = load_queue(&mut core_collection);
+        resume_on_startup(&mut core_collection, &settings);
+        let mut popus = PopUps {
+            error: ErrorInterface {
+                value: errors.join("\n"),
+                show: !errors.is_empty(),
+            },
+            ..Default::default()
+        };
+        popus.download.threading = Threading::for_threads(settings.default_threads);
+        popus.batch.threading = Threading::for_threads(settings.default_threads);
+        let connected_to_net = Connected::default();
+        *connected_to_net.probe.lock() = Probe::from_settings(&settings);
         Self {
             inner: core_collection,
-            popus: PopUps::default(),
-            connected_to_net: Connected::default(),
+            popus,
+            connected_to_net,
             select_all: false,
+            queue,
+            retry_policy: RetryPolicy::from_settings(&settings),
+            bucket: Arc::default(),
+            rpc: RpcServer::default(),
+            history: load_history(),
+            settings,
+            scheduler: load_schedule(),
+            resolver: Resolver::default(),
+            supervisor: Supervisor::default(),
+            credentials: load_credentials(),
             file_channel: std::sync::mpsc::channel(),
+            auth_channel: std::sync::mpsc::channel(),
         }
     }
 }
 
 fn main() -> Result<(), eframe::Error> {
+    history::local_offset();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
     let options = eframe::NativeOptions::default();
     eframe::run_native(
         "Download Manager",
@@ -196,6 +465,19 @@ fn main() -> Result<(), eframe::Error> {
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        sync_rpc_server(self, ctx);
+        process_resolved(self);
+        process_auth_prompts(self);
+        process_dropped_files(self, ctx);
+        handle_rpc_calls(self);
+        process_download_events(self);
+        start_connectivity_check(self);
+        process_connectivity(self);
+        start_schedule_timer(self, ctx);
+        process_schedule(self);
+        process_download_timers(self);
+        process_bandwidth(self);
+        supervise(self);
         egui::CentralPanel::default().show(ctx, |ui| {
             init_menu_bar(self, ui);
             ui.add(Separator::grow(Separator::default(), ui.available_width()));
@@ -205,6 +487,9 @@ impl eframe::App for MyApp {
         if self.popus.download.show {
             show_input_window(ctx, self);
         }
+        if self.popus.batch.show {
+            show_batch_window(ctx, self);
+        }
         ctx.request_repaint();
         if self.popus.confirm.show {
             let task = (self.popus.confirm.task)();
@@ -216,6 +501,21 @@ impl eframe::App for MyApp {
                 task,
             );
         }
+        if self.popus.settings.show {
+            show_settings_window(ctx, self);
+        }
+        if self.popus.schedule.show {
+            show_schedule_window(ctx, self);
+        }
+        if self.popus.timer.show {
+            show_timer_window(ctx, self);
+        }
+        if self.popus.details.show {
+            show_details_window(ctx, self);
+        }
+        if self.popus.credentials.show {
+            show_credentials_window(ctx, self);
+        }
         if self.popus.bandwidth.show {
             show_bandwidth_edit_window(ctx, self, &self.popus.bandwidth.to_edit.clone());
         }
@@ -223,5 +523,14 @@ impl eframe::App for MyApp {
             show_error_window(ctx, self, &self.popus.error.value.clone());
         }
         select_all(self);
+        process_queue(self);
+        process_running_state(self);
+        process_discovery(self);
+        process_verification(self);
+        process_history(self);
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.supervisor.shutdown();
     }
 }
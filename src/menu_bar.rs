@@ -1,6 +1,17 @@
-use crate::MyApp;
-use eframe::egui::{menu, Color32};
-use std::fs::{read_dir, remove_file};
+use crate::{
+    config::{save_settings, MAX_ATTEMPTS},
+    extern_windows::{open_credentials, open_schedule, open_settings},
+    metadata::remove_metadata,
+    mirrors::mirror_part_path,
+    queue::{enqueue, file_key},
+    rpc::generate_token,
+    Core, MyApp,
+};
+use eframe::egui::{menu, Color32, DragValue, TextEdit};
+use std::{
+    fs::{remove_dir_all, remove_file},
+    path::Path,
+};
 
 pub fn init_menu_bar(interface: &mut MyApp, ui: &mut eframe::egui::Ui) {
     menu::bar(ui, |ui| {
@@ -13,16 +24,43 @@ pub fn init_menu_bar(interface: &mut MyApp, ui: &mut eframe::egui::Ui) {
                     if ui.button("Add Download").clicked() {
                         interface.popus.download.show = true;
                     }
+                    if ui.button("Add batch").clicked() {
+                        interface.popus.batch.show = true;
+                        ui.close_menu();
+                    }
                     if ui.button("Resume all").clicked() {
                         for core in interface.inner.iter_mut() {
-                            core.file.status.0.send(true).unwrap();
+                            enqueue(core);
                         }
                     }
                     if ui.button("Pause all").clicked() {
                         for core in interface.inner.iter_mut() {
+                            core.queued = false;
                             core.file.status.0.send(false).unwrap();
                         }
                     }
+                    if ui.button("Bandwidth schedule").clicked() {
+                        open_schedule(interface);
+                        ui.close_menu();
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Max active downloads");
+                        ui.add(DragValue::new(&mut interface.queue.max_active).range(1..=32));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Max retry attempts");
+                        let response = ui.add(
+                            DragValue::new(&mut interface.retry_policy.max_attempts)
+                                .range(1..=MAX_ATTEMPTS),
+                        );
+                        if response.changed() {
+                            interface.settings.max_attempts = interface.retry_policy.max_attempts;
+                            if let Err(e) = save_settings(&interface.settings) {
+                                interface.popus.error.value = e;
+                                interface.popus.error.show = true;
+                            }
+                        }
+                    });
                     if ui.button("Delete all completed").clicked() {
                         interface.inner.retain(|core| {
                             !core
@@ -39,6 +77,15 @@ pub fn init_menu_bar(interface: &mut MyApp, ui: &mut eframe::egui::Ui) {
                         });
                     }
                 });
+                ui.menu_button("Remote", |ui| {
+                    remote_button_content(interface, ui);
+                });
+                if ui.button("Credentials").clicked() {
+                    open_credentials(interface);
+                }
+                if ui.button("Settings").clicked() {
+                    open_settings(interface);
+                }
             });
         });
     });
@@ -83,46 +130,86 @@ fn file_button_content(interface: &mut MyApp, ui: &mut eframe::egui::Ui) {
         interface.popus.confirm.show = true;
     }
 }
+fn remote_button_content(interface: &mut MyApp, ui: &mut eframe::egui::Ui) {
+    ui.checkbox(&mut interface.rpc.enabled, "Enable JSON-RPC server");
+    // The running server keeps the port, origin and token it was started with
+    let editable = !interface.rpc.enabled;
+    ui.horizontal(|ui| {
+        ui.label("Port");
+        ui.add_enabled(
+            editable,
+            TextEdit::singleline(&mut interface.rpc.port).desired_width(60.0),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("Allowed origin");
+        ui.add_enabled(
+            editable,
+            TextEdit::singleline(&mut interface.rpc.origin)
+                .hint_text("none")
+                .desired_width(160.0),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("Token");
+        ui.label(&interface.rpc.token);
+    });
+    ui.horizontal(|ui| {
+        if ui.button("Copy token").clicked() {
+            ui.output_mut(|output| output.copied_text = interface.rpc.token.clone());
+        }
+        if ui
+            .add_enabled(editable, eframe::egui::Button::new("New token"))
+            .clicked()
+        {
+            interface.rpc.token = generate_token();
+        }
+    });
+}
+// Removes the file, whatever is left of its parts and the metadata next to it
+fn remove_from_disk(core: &Core) -> Result<(), String> {
+    let dir = Path::new(&core.file.dir);
+    let path = dir.join(&core.file.name_on_disk);
+    let tmp_path = dir.join(format!(".{}.metadata", core.file.name_on_disk));
+    let parts = dir.join(format!(".{}", core.file.name_on_disk));
+    remove_metadata(&core.file);
+    if parts.is_dir() {
+        remove_dir_all(parts).map_err(|e| e.to_string())?;
+    }
+    if path.is_dir() {
+        remove_dir_all(&path).map_err(|e| e.to_string())?;
+    } else if path.exists() {
+        remove_file(&path).map_err(|e| e.to_string())?;
+    }
+    if tmp_path.exists() {
+        remove_file(tmp_path).map_err(|e| e.to_string())?;
+    }
+    let mirror_part = mirror_part_path(&core.file);
+    if mirror_part.exists() {
+        remove_file(mirror_part).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
 fn delete_all_files_from_disk(interface: &mut MyApp) {
-    let dir = match read_dir("Downloads") {
-        Ok(dir) => dir,
-        Err(e) => {
-            interface.popus.error.value = e.to_string();
+    for core in interface.inner.iter() {
+        interface.supervisor.cancel(&file_key(&core.file));
+        let _ = core.file.status.0.send(false);
+        if let Err(e) = remove_from_disk(core) {
+            interface.popus.error.value = e;
             interface.popus.error.show = true;
-            return;
         }
-    };
-    for file in dir {
-        let path = match file {
-            Ok(file) => file.path(),
-            Err(e) => {
-                interface.popus.error.value = e.to_string();
-                interface.popus.error.show = true;
-                return;
-            }
-        };
-        remove_file(path).unwrap();
     }
     interface.inner.clear();
 }
 fn remove_selected_from_disk(app: &mut MyApp) {
     app.inner.retain(|core| {
         if core.selected {
-            let path = format!("Downloads/{}", core.file.name_on_disk);
-            let tmp_path = format!("Downloads/.{}.metadata", core.file.name_on_disk);
-            match remove_file(path) {
-                Ok(_) => {}
-                Err(e) => {
-                    app.popus.error.value = e.to_string();
-                    app.popus.error.show = true;
-                }
-            }
-            match remove_file(tmp_path) {
-                Ok(_) => {}
-                Err(e) => {
-                    app.popus.error.value = e.to_string();
-                    app.popus.error.show = true;
-                }
+            // Stopped before its files go away, not on the next frame
+            app.supervisor.cancel(&file_key(&core.file));
+            let _ = core.file.status.0.send(false);
+            if let Err(e) = remove_from_disk(core) {
+                app.popus.error.value = e;
+                app.popus.error.show = true;
             }
             return false;
         }
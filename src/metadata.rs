@@ -0,0 +1,121 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+use dl::file2dl::File2Dl;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::Checksum,
+    mirrors::{done_bytes, mirror_part_path},
+    request::{own_file, RequestOptions},
+};
+
+// App side settings of a download, kept next to the metadata written by the engine
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    pub checksum: Option<Checksum>,
+    pub computed_digest: Option<String>,
+    // Bytes per second, the engine's own value may hold a share of the global limit
+    pub bandwidth_limit: Option<usize>,
+    // Unix times of a scheduled start or stop
+    pub start_at: Option<u64>,
+    pub stop_at: Option<u64>,
+    // Extra urls serving the same file, and the chunks they already delivered
+    pub mirrors: Vec<String>,
+    pub mirror_chunks: Vec<usize>,
+    // Headers, cookies and user agent the server wants, and the size it reported with them
+    pub request: RequestOptions,
+    pub total_size: Option<usize>,
+    // Sends the saved credentials of its host, looked up on every attempt so edits apply
+    pub authenticated: bool,
+    // Running or waiting in the queue when last seen, a crash leaves it set
+    pub running: bool,
+    // Overrides the global resume on startup setting
+    pub resume_on_startup: Option<bool>,
+    // Set once the segment downloader owns the file, the engine may have nothing to load it from
+    pub link: String,
+    pub content_type: String,
+}
+impl Metadata {
+    // Mirrors, custom headers and credentials need our own segment downloader, the engine can do none of them
+    pub fn segmented(&self) -> bool {
+        !self.mirrors.is_empty()
+            || !self.request.is_empty()
+            || self.authenticated
+            || !self.link.is_empty()
+    }
+}
+
+pub fn metadata_path(file: &File2Dl) -> PathBuf {
+    Path::new(&file.dir).join(format!(".{}.app.json", file.name_on_disk))
+}
+
+pub fn load_metadata(file: &File2Dl) -> Metadata {
+    fs::read_to_string(metadata_path(file))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Metadata>(&content).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_metadata(file: &File2Dl, metadata: &Metadata) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(metadata).map_err(|e| e.to_string())?;
+    fs::write(metadata_path(file), json).map_err(|e| e.to_string())
+}
+
+pub fn remove_metadata(file: &File2Dl) {
+    let _ = fs::remove_file(metadata_path(file));
+}
+
+// Rebuilds the files of dir the engine did not load, from the link and size our metadata kept
+pub fn own_files(dir: &str, loaded: &[File2Dl]) -> Vec<File2Dl> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let name = match file_name
+            .strip_prefix('.')
+            .and_then(|name| name.strip_suffix(".app.json"))
+        {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => continue,
+        };
+        if loaded.iter().any(|file| file.name_on_disk == name) {
+            continue;
+        }
+        let metadata = fs::read_to_string(entry.path())
+            .ok()
+            .and_then(|content| serde_json::from_str::<Metadata>(&content).ok())
+            .unwrap_or_default();
+        if metadata.link.is_empty() {
+            continue;
+        }
+        let total_size = metadata.total_size.unwrap_or_default();
+        let file = own_file(
+            &metadata.link,
+            dir,
+            &name,
+            total_size,
+            &metadata.content_type,
+            metadata.bandwidth_limit.unwrap_or_default(),
+        );
+        // The part file is renamed to the target once every chunk arrived
+        let done = if mirror_part_path(&file).exists() {
+            done_bytes(&metadata.mirror_chunks, total_size)
+        } else if Path::new(dir).join(&name).is_file() {
+            file.complete.store(true, Ordering::Relaxed);
+            total_size
+        } else {
+            0
+        };
+        file.size_on_disk.store(done, Ordering::Relaxed);
+        files.push(file);
+    }
+    files
+}
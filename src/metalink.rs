@@ -0,0 +1,313 @@
+use std::path::Path;
+
+use eframe::egui;
+
+use crate::{
+    checksum::{parse_checksum, Checksum, HashAlgorithm},
+    errors::DownloadError,
+    proxy::ProxySettings,
+    request::fetch_file,
+    resolve::{free_name, NewFile, RESOLVE_TIMEOUT},
+    MyApp,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetalinkFile {
+    pub name: String,
+    pub size: Option<usize>,
+    pub checksum: Option<Checksum>,
+    // Best first, only the ones the downloader can fetch
+    pub urls: Vec<String>,
+}
+
+// Works for a url or a local path, the query string does not count
+pub fn is_metalink(source: &str) -> bool {
+    let path = source
+        .trim()
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    path.ends_with(".metalink") || path.ends_with(".meta4")
+}
+
+fn is_http(url: &str) -> bool {
+    let url = url.to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+pub async fn read_metalink(source: &str, proxy: &ProxySettings) -> Result<String, String> {
+    let source = source.trim();
+    if is_http(source) {
+        proxy
+            .apply_global(reqwest::Client::builder())?
+            .timeout(RESOLVE_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())?
+            .get(source)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| DownloadError::from_error(&e).to_string())?
+            .text()
+            .await
+            .map_err(|e| e.to_string())
+    } else {
+        let path = source.strip_prefix("file://").unwrap_or(source);
+        tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Could not read {}: {}", path, e))
+    }
+}
+
+fn algorithm_rank(algorithm: HashAlgorithm) -> u8 {
+    match algorithm {
+        HashAlgorithm::Sha256 => 0,
+        HashAlgorithm::Sha1 => 1,
+        HashAlgorithm::Md5 => 2,
+    }
+}
+
+fn parse_file(node: roxmltree::Node) -> Result<MetalinkFile, String> {
+    let name = node
+        .attribute("name")
+        .ok_or("A file in the metalink has no name")?;
+    // Names may carry directories, the file still lands in the chosen folder
+    let name = Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| format!("Invalid file name in the metalink: {}", name))?;
+    let size = node
+        .descendants()
+        .find(|child| child.tag_name().name() == "size")
+        .and_then(|size| size.text())
+        .and_then(|size| size.trim().parse::<usize>().ok());
+    // Piece hashes only cover parts of the file
+    let checksum = node
+        .descendants()
+        .filter(|child| child.tag_name().name() == "hash")
+        .filter(|hash| {
+            hash.parent_element()
+                .is_some_and(|parent| parent.tag_name().name() != "pieces")
+        })
+        .filter_map(|hash| {
+            let kind = hash.attribute("type")?;
+            parse_checksum(&format!("{}:{}", kind, hash.text()?.trim())).ok()
+        })
+        .min_by_key(|checksum| algorithm_rank(checksum.algorithm));
+    // Metalink 4 ranks urls by priority (lower first), version 3 by preference (higher first)
+    let mut urls = node
+        .descendants()
+        .filter(|child| child.tag_name().name() == "url")
+        .filter_map(|url| {
+            let link = url.text()?.trim().to_string();
+            if !is_http(&link) {
+                return None;
+            }
+            let rank = match (url.attribute("priority"), url.attribute("preference")) {
+                (Some(priority), _) => priority.parse::<u32>().unwrap_or(u32::MAX),
+                (None, Some(preference)) => {
+                    100u32.saturating_sub(preference.parse::<u32>().unwrap_or(0))
+                }
+                (None, None) => u32::MAX,
+            };
+            Some((rank, link))
+        })
+        .collect::<Vec<(u32, String)>>();
+    urls.sort_by_key(|(rank, _)| *rank);
+    let mut unique = Vec::new();
+    for (_, link) in urls {
+        if !unique.contains(&link) {
+            unique.push(link);
+        }
+    }
+    Ok(MetalinkFile {
+        name,
+        size,
+        checksum,
+        urls: unique,
+    })
+}
+
+// Handles both RFC 5854 (.meta4) and the older 3.0 format (.metalink)
+pub fn parse_metalink(content: &str) -> Result<Vec<MetalinkFile>, String> {
+    let document =
+        roxmltree::Document::parse(content).map_err(|e| format!("Invalid metalink: {}", e))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "metalink" {
+        return Err("Not a metalink file".to_string());
+    }
+    let files = root
+        .descendants()
+        .filter(|node| node.tag_name().name() == "file")
+        .map(parse_file)
+        .collect::<Result<Vec<MetalinkFile>, String>>()?;
+    if files.is_empty() {
+        return Err("The metalink lists no files".to_string());
+    }
+    Ok(files)
+}
+
+// The first url that answers names the file, the others become its mirrors
+async fn resolve_file(
+    mut entry: MetalinkFile,
+    dir: &str,
+    bandwidth: f64,
+    taken: &mut Vec<String>,
+    proxy: &ProxySettings,
+) -> Result<(MetalinkFile, NewFile), String> {
+    if entry.urls.is_empty() {
+        return Err(format!("{}: no http or https url", entry.name));
+    }
+    let mut last_error = String::default();
+    for (index, link) in entry.urls.iter().enumerate() {
+        let mut new =
+            match fetch_file(link, dir, bandwidth, &Default::default(), &None, proxy).await {
+                Ok(new) => new,
+                Err(e) => {
+                    last_error = e.to_string();
+                    continue;
+                }
+            };
+        let file = &mut new.file;
+        if let Some(size) = entry.size {
+            if file.url.total_size != 0 && file.url.total_size != size {
+                last_error = format!(
+                    "{} is {} bytes instead of {}",
+                    link, file.url.total_size, size
+                );
+                continue;
+            }
+        }
+        // The engine names the file after the url, the metalink knows better.
+        // Entries from different metalink directories can share a name, so can downloads already in the list
+        let name = free_name(dir, &entry.name, taken);
+        file.name_on_disk = name.clone();
+        file.url.filename = name.clone();
+        taken.push(name);
+        let primary = entry.urls.remove(index);
+        entry.urls.insert(0, primary);
+        return Ok((entry, new));
+    }
+    Err(format!("{}: {}", entry.name, last_error))
+}
+
+// taken holds the names of the downloads already in dir
+pub async fn import_metalink(
+    source: &str,
+    dir: &str,
+    bandwidth: f64,
+    mut taken: Vec<String>,
+    proxy: &ProxySettings,
+) -> Result<Vec<Result<(MetalinkFile, NewFile), String>>, String> {
+    let content = read_metalink(source, proxy).await?;
+    let mut resolved = Vec::new();
+    for entry in parse_metalink(&content)? {
+        resolved.push(resolve_file(entry, dir, bandwidth, &mut taken, proxy).await);
+    }
+    Ok(resolved)
+}
+
+// A metalink dropped on the window opens the add dialog with it
+pub fn process_dropped_files(app: &mut MyApp, ctx: &egui::Context) {
+    let dropped = ctx.input(|input| input.raw.dropped_files.clone());
+    for file in dropped {
+        let path = match file.path {
+            Some(path) => path.to_string_lossy().to_string(),
+            None => continue,
+        };
+        if is_metalink(&path) {
+            app.popus.download.url = path;
+            app.popus.download.error = String::default();
+            app.popus.download.show = true;
+        } else {
+            app.popus.error.value =
+                format!("Only .metalink and .meta4 files can be dropped: {}", path);
+            app.popus.error.show = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+    #[test]
+    fn metalink4_orders_by_priority() {
+        let content = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="dir/example.iso">
+    <size>1024</size>
+    <hash type="sha-1">{SHA1}</hash>
+    <hash type="sha-256">{SHA256}</hash>
+    <pieces length="512" type="sha-256">
+      <hash>{SHA256}</hash>
+    </pieces>
+    <url priority="2">https://b.example.com/example.iso</url>
+    <url priority="1">https://a.example.com/example.iso</url>
+    <url priority="3">ftp://c.example.com/example.iso</url>
+  </file>
+</metalink>"#
+        );
+        let files = parse_metalink(&content).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].name, "example.iso");
+        assert_eq!(files[0].size, Some(1024));
+        assert_eq!(
+            files[0].urls,
+            vec![
+                "https://a.example.com/example.iso",
+                "https://b.example.com/example.iso"
+            ]
+        );
+        let checksum = files[0].checksum.clone().unwrap();
+        assert_eq!(checksum.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(checksum.digest, SHA256);
+    }
+
+    #[test]
+    fn metalink3_orders_by_preference() {
+        let content = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink version="3.0" xmlns="http://www.metalinker.org/">
+  <files>
+    <file name="example.tar.gz">
+      <verification>
+        <hash type="sha1">{SHA1}</hash>
+        <pieces length="512" type="sha256">
+          <hash piece="0">{SHA256}</hash>
+        </pieces>
+      </verification>
+      <resources>
+        <url type="http" preference="10">http://low.example.com/example.tar.gz</url>
+        <url type="http" preference="90">http://high.example.com/example.tar.gz</url>
+      </resources>
+    </file>
+  </files>
+</metalink>"#
+        );
+        let files = parse_metalink(&content).unwrap();
+        assert_eq!(
+            files[0].urls,
+            vec![
+                "http://high.example.com/example.tar.gz",
+                "http://low.example.com/example.tar.gz"
+            ]
+        );
+        // The piece hash is not a hash of the whole file
+        let checksum = files[0].checksum.clone().unwrap();
+        assert_eq!(checksum.algorithm, HashAlgorithm::Sha1);
+        assert_eq!(checksum.digest, SHA1);
+    }
+
+    #[test]
+    fn other_documents_are_rejected() {
+        assert!(parse_metalink("<rss></rss>").is_err());
+        assert!(parse_metalink("<metalink></metalink>").is_err());
+        assert!(parse_metalink("not xml").is_err());
+    }
+}
@@ -0,0 +1,603 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, mpsc::Sender, Arc},
+    time::{Duration, Instant},
+};
+
+use dl::file2dl::File2Dl;
+use eframe::egui::mutex::Mutex;
+use futures_util::StreamExt;
+use reqwest::{
+    header::{CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    task::AbortHandle,
+};
+
+use crate::{
+    bandwidth::TokenBucket,
+    credentials::{Credential, Credentials},
+    errors::DownloadError,
+    metadata::{save_metadata, Metadata},
+    proxy::ProxySettings,
+    request::RequestOptions,
+    retry::DownloadEvent,
+    Core,
+};
+
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+// A mirror is dropped after this many failed chunks in a row
+const MAX_MIRROR_FAILURES: u32 = 3;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MirrorState {
+    Checking,
+    Active,
+    Dropped(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct MirrorStat {
+    pub url: String,
+    pub state: MirrorState,
+    pub bytes: usize,
+    pub rate: usize,
+    failures: u32,
+    window_start: Instant,
+    window_bytes: usize,
+}
+impl MirrorStat {
+    fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            state: MirrorState::Checking,
+            bytes: 0,
+            rate: 0,
+            failures: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    fn record(&mut self, bytes: usize) {
+        self.bytes += bytes;
+        self.window_bytes += bytes;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.rate = (self.window_bytes as f64 / elapsed.as_secs_f64()) as usize;
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
+}
+
+// Everything a multi source download needs besides the file, shared with the ui through the core
+#[derive(Clone)]
+pub struct MirrorJob {
+    pub urls: Vec<String>,
+    pub done: Arc<Mutex<Vec<usize>>>,
+    pub stats: Arc<Mutex<Vec<MirrorStat>>>,
+    pub request: RequestOptions,
+    // Saved credentials of each url's host, in the order of urls
+    pub auth: Vec<Option<Credential>>,
+    // The proxy of the settings when the job started
+    pub proxy: ProxySettings,
+    // The global cap, shared with the other segmented downloads
+    pub bucket: Arc<TokenBucket>,
+}
+
+pub fn mirror_job(
+    core: &Core,
+    credentials: &Credentials,
+    proxy: &ProxySettings,
+    bucket: &Arc<TokenBucket>,
+) -> Option<MirrorJob> {
+    if !core.metadata.segmented() {
+        return None;
+    }
+    Some(job_for(
+        &core.file,
+        &core.metadata,
+        core.mirror_stats.clone(),
+        credentials,
+        proxy,
+        bucket,
+    ))
+}
+
+pub fn job_for(
+    file: &File2Dl,
+    metadata: &Metadata,
+    stats: Arc<Mutex<Vec<MirrorStat>>>,
+    credentials: &Credentials,
+    proxy: &ProxySettings,
+    bucket: &Arc<TokenBucket>,
+) -> MirrorJob {
+    let mut urls = vec![file.url.link.clone()];
+    urls.extend(metadata.mirrors.iter().cloned());
+    MirrorJob {
+        done: Arc::new(Mutex::new(metadata.mirror_chunks.clone())),
+        stats,
+        request: metadata.request.clone(),
+        auth: urls.iter().map(|url| credentials.for_link(url)).collect(),
+        urls,
+        proxy: proxy.clone(),
+        bucket: bucket.clone(),
+    }
+}
+
+// Hands a download the engine started to the segment downloader for good, the engine can neither send credentials nor use a proxy
+pub fn take_over(file: &File2Dl, metadata: &mut Metadata) -> Result<(), String> {
+    let total_size = file.url.total_size;
+    if total_size == 0 {
+        return Err(format!(
+            "{} did not tell the size of {}, which is needed to fetch it in chunks",
+            file.url.link, file.name_on_disk
+        ));
+    }
+    let target = Path::new(&file.dir).join(&file.name_on_disk);
+    let part = mirror_part_path(file);
+    // A single threaded engine download wrote the start of the file, its whole chunks count as done
+    if target.is_file() && !part.exists() {
+        let size = std::fs::metadata(&target)
+            .map(|metadata| metadata.len() as usize)
+            .map_err(|e| e.to_string())?
+            .min(total_size);
+        std::fs::rename(&target, &part).map_err(|e| e.to_string())?;
+        let chunks = if size == total_size {
+            chunk_count(total_size)
+        } else {
+            size / CHUNK_SIZE
+        };
+        metadata.mirror_chunks = (0..chunks).collect();
+    }
+    file.size_on_disk.store(
+        done_bytes(&metadata.mirror_chunks, total_size),
+        Ordering::Relaxed,
+    );
+    metadata.link = file.url.link.clone();
+    metadata.content_type = file.url.content_type.clone();
+    metadata.total_size = Some(total_size);
+    save_metadata(file, metadata)
+}
+
+// One url per line, the first one names the file and the others are mirrors of it
+pub fn split_urls(text: &str) -> (String, Vec<String>) {
+    let mut urls = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if !urls.iter().any(|url| url == line) {
+            urls.push(line.to_string());
+        }
+    }
+    if urls.is_empty() {
+        return (String::default(), Vec::new());
+    }
+    let primary = urls.remove(0);
+    (primary, urls)
+}
+
+// The data only gets its real name once every chunk is in, so a half written file is never mistaken for a finished one
+pub fn mirror_part_path(file: &File2Dl) -> PathBuf {
+    Path::new(&file.dir).join(format!(".{}.part", file.name_on_disk))
+}
+
+pub fn chunk_count(total_size: usize) -> usize {
+    total_size.div_ceil(CHUNK_SIZE)
+}
+
+fn chunk_range(index: usize, total_size: usize) -> (usize, usize) {
+    let start = index * CHUNK_SIZE;
+    (start, (start + CHUNK_SIZE).min(total_size))
+}
+
+pub fn done_bytes(done: &[usize], total_size: usize) -> usize {
+    done.iter()
+        .map(|index| {
+            let (start, end) = chunk_range(*index, total_size);
+            end - start
+        })
+        .sum()
+}
+
+// Mirrors have to agree on the size and accept ranges, anything else cannot share the work
+async fn probe_mirror(
+    client: &reqwest::Client,
+    url: &str,
+    auth: &Option<Credential>,
+    total_size: usize,
+) -> Result<(), DownloadError> {
+    let response = authorized(client.get(url), auth)
+        .header(RANGE, "bytes=0-0")
+        .send()
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Err(DownloadError::HttpStatus {
+            status: 401,
+            details: format!("{} wants credentials", url),
+        });
+    }
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::Other(format!(
+            "No range support ({})",
+            response.status()
+        )));
+    }
+    let size = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit('/').next())
+        .and_then(|size| size.parse::<usize>().ok());
+    match size {
+        Some(size) if size == total_size => Ok(()),
+        Some(size) => Err(DownloadError::Other(format!(
+            "Different size ({} bytes)",
+            size
+        ))),
+        None => Err(DownloadError::Other("Unknown size".to_string())),
+    }
+}
+
+fn authorized(
+    request: reqwest::RequestBuilder,
+    auth: &Option<Credential>,
+) -> reqwest::RequestBuilder {
+    match auth {
+        Some(credential) => credential.apply(request),
+        None => request,
+    }
+}
+
+async fn wait_until_resumed(file: &File2Dl) {
+    let mut status = file.status.1.clone();
+    while !*status.borrow() {
+        if status.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn download_chunk(
+    client: &reqwest::Client,
+    file: &File2Dl,
+    mirror: usize,
+    index: usize,
+    workers: usize,
+    job: &MirrorJob,
+) -> Result<bool, DownloadError> {
+    let total_size = file.url.total_size;
+    let (start, end) = chunk_range(index, total_size);
+    let url = job.stats.lock()[mirror].url.clone();
+    let response = authorized(client.get(&url), &job.auth[mirror])
+        .header(RANGE, format!("bytes={}-{}", start, end - 1))
+        .send()
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::HttpStatus {
+            status: response.status().as_u16(),
+            details: format!("{} answered {} to a range request", url, response.status()),
+        });
+    }
+    let mut output = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(mirror_part_path(file))
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    output
+        .seek(std::io::SeekFrom::Start(start as u64))
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    let mut stream = response.bytes_stream();
+    let mut written = 0;
+    let started = Instant::now();
+    while let Some(bytes) = stream.next().await {
+        if !*file.status.1.borrow() {
+            // Whatever was written gets downloaded again with the chunk
+            file.size_on_disk.fetch_sub(written, Ordering::Relaxed);
+            return Ok(false);
+        }
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                file.size_on_disk.fetch_sub(written, Ordering::Relaxed);
+                return Err(DownloadError::from_error(&e));
+            }
+        };
+        if let Err(e) = output.write_all(&bytes).await {
+            file.size_on_disk.fetch_sub(written, Ordering::Relaxed);
+            return Err(DownloadError::from_error(&e));
+        }
+        written += bytes.len();
+        file.size_on_disk.fetch_add(bytes.len(), Ordering::Relaxed);
+        job.stats.lock()[mirror].record(bytes.len());
+        // Every worker gets an equal part of the per file limit, and all of them draw from the global bucket
+        let limit = file.bandwidth_chosen.load(Ordering::Relaxed) / workers.max(1);
+        let mut wait = job.bucket.take(bytes.len());
+        if limit > 0 {
+            let expected = Duration::from_secs_f64(written as f64 / limit as f64);
+            if let Some(ahead) = expected.checked_sub(started.elapsed()) {
+                wait = wait.max(ahead);
+            }
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+    if written != end - start {
+        file.size_on_disk.fetch_sub(written, Ordering::Relaxed);
+        return Err(DownloadError::Network(format!(
+            "{} ended the chunk after {} of {} bytes",
+            url,
+            written,
+            end - start
+        )));
+    }
+    output
+        .flush()
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    Ok(true)
+}
+
+async fn worker(
+    client: reqwest::Client,
+    file: File2Dl,
+    mirror: usize,
+    workers: usize,
+    pending: Arc<Mutex<VecDeque<usize>>>,
+    job: MirrorJob,
+    tx: Sender<DownloadEvent>,
+) -> Option<DownloadError> {
+    let mut last_error = None;
+    loop {
+        wait_until_resumed(&file).await;
+        if !matches!(job.stats.lock()[mirror].state, MirrorState::Active) {
+            return last_error;
+        }
+        let index = match pending.lock().pop_front() {
+            Some(index) => index,
+            None => return None,
+        };
+        match download_chunk(&client, &file, mirror, index, workers, &job).await {
+            Ok(true) => {
+                job.done.lock().push(index);
+                job.stats.lock()[mirror].failures = 0;
+                let _ = tx.send(DownloadEvent::ChunkDone(index));
+            }
+            Ok(false) => pending.lock().push_front(index),
+            Err(error) => {
+                pending.lock().push_back(index);
+                let mut stats = job.stats.lock();
+                let stat = &mut stats[mirror];
+                stat.failures += 1;
+                if stat.failures >= MAX_MIRROR_FAILURES || !error.is_retryable() {
+                    stat.state = MirrorState::Dropped(error.to_string());
+                    stat.rate = 0;
+                }
+                last_error = Some(error);
+            }
+        }
+    }
+}
+
+struct Workers(Vec<AbortHandle>);
+impl Drop for Workers {
+    fn drop(&mut self) {
+        for worker in self.0.iter() {
+            worker.abort();
+        }
+    }
+}
+
+// Spreads the chunks that are still missing over every usable mirror, the fastest ones simply take more of them
+pub async fn mirror_dl(
+    file: &File2Dl,
+    threads: usize,
+    job: &MirrorJob,
+    tx: &Sender<DownloadEvent>,
+) -> Result<(), DownloadError> {
+    let total_size = file.url.total_size;
+    // Every chunk of every mirror carries the headers and goes through the same proxy
+    let client = job
+        .request
+        .client_builder(&job.proxy)
+        .map_err(DownloadError::Other)?
+        .connect_timeout(PROBE_TIMEOUT)
+        .build()
+        .map_err(|e| DownloadError::from_error(&e))?;
+    {
+        let mut stats = job.stats.lock();
+        if stats.len() != job.urls.len() {
+            *stats = job.urls.iter().map(|url| MirrorStat::new(url)).collect();
+        }
+    }
+    // Nothing to fetch, and a server may refuse any range of an empty file
+    if total_size == 0 {
+        return finish_part(file).await;
+    }
+    // Every attempt probes again, a mirror dropped during an outage deserves another chance
+    let mut unauthorized = None;
+    for (mirror, url) in job.urls.iter().enumerate() {
+        job.stats.lock()[mirror].failures = 0;
+        let state = match probe_mirror(&client, url, &job.auth[mirror], total_size).await {
+            Ok(_) => MirrorState::Active,
+            Err(e) => {
+                let reason = e.details();
+                if e.is_unauthorized() {
+                    unauthorized = Some(e);
+                }
+                MirrorState::Dropped(reason)
+            }
+        };
+        job.stats.lock()[mirror].state = state;
+    }
+    let usable = job
+        .stats
+        .lock()
+        .iter()
+        .enumerate()
+        .filter(|(_, stat)| stat.state == MirrorState::Active)
+        .map(|(mirror, _)| mirror)
+        .collect::<Vec<usize>>();
+    if usable.is_empty() {
+        // Asking for credentials helps more than a generic failure
+        return Err(
+            unauthorized.unwrap_or(DownloadError::Other("No usable mirror left".to_string()))
+        );
+    }
+    let pending = {
+        let done = job.done.lock();
+        (0..chunk_count(total_size))
+            .filter(|index| !done.contains(index))
+            .collect::<VecDeque<usize>>()
+    };
+    let pending = Arc::new(Mutex::new(pending));
+    let workers = threads.max(usable.len());
+    let rate_file = file.clone();
+    let rate_stats = job.stats.clone();
+    let rate = tokio::spawn(async move {
+        loop {
+            let total = rate_stats
+                .lock()
+                .iter()
+                .map(|stat| stat.rate)
+                .sum::<usize>();
+            rate_file.transfer_rate.store(total, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+    let mut handles = (0..workers)
+        .map(|worker_index| {
+            tokio::spawn(worker(
+                client.clone(),
+                file.clone(),
+                usable[worker_index % usable.len()],
+                workers,
+                pending.clone(),
+                job.clone(),
+                tx.clone(),
+            ))
+        })
+        .collect::<Vec<_>>();
+    // Cancelling the download drops this future, the workers must not outlive it
+    let _workers = Workers(
+        handles
+            .iter()
+            .map(|handle| handle.abort_handle())
+            .chain(std::iter::once(rate.abort_handle()))
+            .collect(),
+    );
+    let mut last_error = None;
+    for handle in handles.iter_mut() {
+        if let Ok(Some(error)) = handle.await {
+            last_error = Some(error);
+        }
+    }
+    rate.abort();
+    file.transfer_rate.store(0, Ordering::Relaxed);
+    if job.done.lock().len() < chunk_count(total_size) {
+        return Err(
+            last_error.unwrap_or(DownloadError::Other("Every mirror was dropped".to_string()))
+        );
+    }
+    finish_part(file).await
+}
+
+// Gives the part file its real name, an empty download never had a chunk create it
+async fn finish_part(file: &File2Dl) -> Result<(), DownloadError> {
+    let part = mirror_part_path(file);
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part)
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    let target = Path::new(&file.dir).join(&file.name_on_disk);
+    let _ = tokio::fs::remove_file(&target).await;
+    tokio::fs::rename(part, target)
+        .await
+        .map_err(|e| DownloadError::from_error(&e))?;
+    file.size_on_disk
+        .store(file.url.total_size, Ordering::Relaxed);
+    file.complete.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::own_file;
+
+    #[tokio::test]
+    async fn empty_file_completes_without_a_request() {
+        let dir = std::env::temp_dir().join(format!("dl-empty-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+        // Nothing listens there, any request would fail
+        let file = own_file("http://127.0.0.1:9/empty", &dir, "empty", 0, "", 0);
+        let job = MirrorJob {
+            urls: vec![file.url.link.clone()],
+            done: Arc::default(),
+            stats: Arc::default(),
+            request: Default::default(),
+            auth: vec![None],
+            proxy: Default::default(),
+            bucket: Arc::default(),
+        };
+        let (tx, _rx) = std::sync::mpsc::channel();
+        mirror_dl(&file, 1, &job, &tx).await.unwrap();
+        let target = Path::new(&dir).join("empty");
+        assert_eq!(std::fs::metadata(&target).unwrap().len(), 0);
+        assert!(file.complete.load(Ordering::Relaxed));
+        assert!(!mirror_part_path(&file).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn take_over_keeps_whole_chunks_of_an_engine_download() {
+        let dir = std::env::temp_dir().join(format!("dl-take-over-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("big"), vec![0u8; CHUNK_SIZE + 10]).unwrap();
+        let dir = dir.to_string_lossy().to_string();
+        let file = own_file("http://127.0.0.1:9/big", &dir, "big", 3 * CHUNK_SIZE, "", 0);
+        let mut metadata = Metadata::default();
+        take_over(&file, &mut metadata).unwrap();
+        assert!(!Path::new(&dir).join("big").exists());
+        assert!(mirror_part_path(&file).is_file());
+        assert_eq!(metadata.mirror_chunks, vec![0]);
+        assert_eq!(metadata.link, file.url.link);
+        assert_eq!(file.size_on_disk.load(Ordering::Relaxed), CHUNK_SIZE);
+        assert!(metadata.segmented());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn take_over_needs_the_size() {
+        let file = own_file(
+            "http://127.0.0.1:9/unknown",
+            "/nonexistent",
+            "unknown",
+            0,
+            "",
+            0,
+        );
+        assert!(take_over(&file, &mut Metadata::default()).is_err());
+    }
+
+    #[test]
+    fn chunks_cover_the_file() {
+        assert_eq!(chunk_count(0), 0);
+        assert_eq!(chunk_count(CHUNK_SIZE), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE + 1), 2);
+        assert_eq!(chunk_range(1, CHUNK_SIZE + 1), (CHUNK_SIZE, CHUNK_SIZE + 1));
+    }
+}
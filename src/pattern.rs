@@ -0,0 +1,207 @@
+// Keeps a typo like [1-1000000] from freezing the ui
+pub const MAX_EXPANDED: usize = 1_000;
+// More than this many downloads are only added once the user confirms
+pub const CONFIRM_EXPANDED: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Choices(Vec<String>),
+}
+
+// [001-120] keeps the zero padding of its start, [a-z] walks letters
+fn parse_range(inner: &str) -> Option<Vec<String>> {
+    let (start, end) = inner.split_once('-')?;
+    if let (Ok(first), Ok(last)) = (start.parse::<u64>(), end.parse::<u64>()) {
+        if first > last {
+            return None;
+        }
+        if last - first >= MAX_EXPANDED as u64 {
+            return Some(Vec::new());
+        }
+        let width = if start.starts_with('0') {
+            start.len()
+        } else {
+            0
+        };
+        return Some(
+            (first..=last)
+                .map(|number| format!("{:0width$}", number, width = width))
+                .collect(),
+        );
+    }
+    let (mut start, mut end) = (start.chars(), end.chars());
+    match (start.next(), start.next(), end.next(), end.next()) {
+        (Some(first), None, Some(last), None)
+            if first.is_ascii_alphabetic()
+                && last.is_ascii_alphabetic()
+                && first.is_ascii_lowercase() == last.is_ascii_lowercase()
+                && first <= last =>
+        {
+            Some((first..=last).map(String::from).collect())
+        }
+        _ => None,
+    }
+}
+
+// Brackets or braces that do not form a pattern stay as they are, an ipv6 host for example
+fn parse_parts(url: &str) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut rest = url;
+    while let Some(open) = rest.find(['[', '{']) {
+        let close = if rest[open..].starts_with('[') {
+            ']'
+        } else {
+            '}'
+        };
+        let choices = rest[open + 1..].find(close).and_then(|length| {
+            let inner = &rest[open + 1..open + 1 + length];
+            let choices = if close == ']' {
+                parse_range(inner)
+            } else if inner.contains(',') {
+                Some(inner.split(',').map(String::from).collect())
+            } else {
+                None
+            };
+            choices.map(|choices| (choices, open + length + 2))
+        });
+        match choices {
+            Some((choices, end)) => {
+                text.push_str(&rest[..open]);
+                parts.push(Part::Text(std::mem::take(&mut text)));
+                parts.push(Part::Choices(choices));
+                rest = &rest[end..];
+            }
+            None => {
+                text.push_str(&rest[..=open]);
+                rest = &rest[open + 1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    parts.push(Part::Text(text));
+    parts
+}
+
+pub fn has_pattern(url: &str) -> bool {
+    parse_parts(url)
+        .iter()
+        .any(|part| matches!(part, Part::Choices(_)))
+}
+
+// Every combination of the patterns, the last pattern changing fastest
+pub fn expand_pattern(url: &str) -> Result<Vec<String>, String> {
+    let mut urls = vec![String::new()];
+    for part in parse_parts(url.trim()) {
+        match part {
+            Part::Text(text) => urls.iter_mut().for_each(|url| url.push_str(&text)),
+            Part::Choices(choices) => {
+                if choices.is_empty() || urls.len() * choices.len() > MAX_EXPANDED {
+                    return Err(format!(
+                        "The pattern expands to more than {} URLs",
+                        MAX_EXPANDED
+                    ));
+                }
+                urls = urls
+                    .iter()
+                    .flat_map(|url| {
+                        choices
+                            .iter()
+                            .map(move |choice| format!("{}{}", url, choice))
+                    })
+                    .collect();
+            }
+        }
+    }
+    Ok(urls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_keeps_zero_padding() {
+        assert_eq!(
+            parse_range("008-011"),
+            Some(
+                vec!["008", "009", "010", "011"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+        assert_eq!(
+            parse_range("9-10"),
+            Some(vec!["9".to_string(), "10".to_string()])
+        );
+    }
+
+    #[test]
+    fn range_walks_letters_of_one_case() {
+        assert_eq!(
+            parse_range("x-z"),
+            Some(vec!["x".to_string(), "y".to_string(), "z".to_string()])
+        );
+        assert_eq!(parse_range("a-Z"), None);
+        assert_eq!(parse_range("5-1"), None);
+        assert_eq!(parse_range("abc"), None);
+    }
+
+    #[test]
+    fn range_does_not_overflow() {
+        assert_eq!(parse_range("1-99999999999999999999"), None);
+        assert_eq!(parse_range(&format!("0-{}", u64::MAX)), Some(Vec::new()));
+    }
+
+    #[test]
+    fn ipv6_host_is_not_a_pattern() {
+        let url = "http://[::1]:8080/file.bin";
+        assert_eq!(parse_parts(url), vec![Part::Text(url.to_string())]);
+        assert!(!has_pattern(url));
+        assert_eq!(
+            expand_pattern("http://[::1]/part[1-2].bin").unwrap(),
+            vec!["http://[::1]/part1.bin", "http://[::1]/part2.bin"]
+        );
+    }
+
+    #[test]
+    fn braces_without_choices_stay() {
+        let url = "https://example.com/file?filter={}&q={x}";
+        assert!(!has_pattern(url));
+        assert_eq!(expand_pattern(url).unwrap(), vec![url.to_string()]);
+        assert_eq!(
+            expand_pattern("https://example.com/{a,b}.zip?q={}").unwrap(),
+            vec![
+                "https://example.com/a.zip?q={}",
+                "https://example.com/b.zip?q={}"
+            ]
+        );
+    }
+
+    #[test]
+    fn last_pattern_changes_fastest() {
+        assert_eq!(
+            expand_pattern("https://example.com/{a,b}[1-2]").unwrap(),
+            vec![
+                "https://example.com/a1",
+                "https://example.com/a2",
+                "https://example.com/b1",
+                "https://example.com/b2"
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_urls_is_an_error() {
+        assert!(expand_pattern("https://example.com/[1-1000000]").is_err());
+        assert!(expand_pattern("https://example.com/[1-100]/[1-100]").is_err());
+        assert_eq!(
+            expand_pattern(&format!("https://example.com/[1-{}]", MAX_EXPANDED))
+                .unwrap()
+                .len(),
+            MAX_EXPANDED
+        );
+    }
+}
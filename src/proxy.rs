@@ -0,0 +1,122 @@
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+
+const SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxySettings {
+    // http://, https:// or socks5://host:port, empty for none
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    // Comma separated hosts that are reached directly, ".example.com" covers its subdomains
+    pub no_proxy: String,
+}
+impl ProxySettings {
+    pub fn is_set(&self) -> bool {
+        !self.url.trim().is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.is_set() {
+            return Ok(());
+        }
+        let url = reqwest::Url::parse(self.url.trim())
+            .map_err(|_| format!("Invalid proxy URL: {}", self.url))?;
+        if !SCHEMES.contains(&url.scheme()) {
+            return Err("The proxy must be http://, https:// or socks5://".to_string());
+        }
+        if url.host_str().is_none() {
+            return Err("The proxy URL has no host".to_string());
+        }
+        if self.password.is_empty() || !self.username.is_empty() {
+            Ok(())
+        } else {
+            Err("A proxy password needs a username".to_string())
+        }
+    }
+
+    // Credentials go in the url, reqwest sends them to the proxy from there
+    fn full_url(&self) -> Option<String> {
+        let mut url = reqwest::Url::parse(self.url.trim()).ok()?;
+        if !self.username.is_empty() {
+            url.set_username(&self.username).ok()?;
+            url.set_password(Some(self.password.as_str()).filter(|password| !password.is_empty()))
+                .ok()?;
+        }
+        Some(url.to_string())
+    }
+
+    // host:port of the proxy itself
+    pub fn address(&self) -> Option<String> {
+        let url = reqwest::Url::parse(self.url.trim()).ok()?;
+        let port = match url.scheme() {
+            "socks5" | "socks5h" => url.port().unwrap_or(1080),
+            _ => url.port_or_known_default()?,
+        };
+        Some(format!("{}:{}", url.host_str()?, port))
+    }
+
+    pub fn bypasses(&self, host: &str) -> bool {
+        let host = host.trim().to_ascii_lowercase();
+        self.no_proxy
+            .split(',')
+            .map(|entry| entry.trim().to_ascii_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .any(|entry| {
+                let domain = entry.trim_start_matches('.');
+                entry == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+            })
+    }
+
+    fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder, String> {
+        let url = match self.full_url() {
+            Some(url) => url,
+            None => return Ok(builder.no_proxy()),
+        };
+        let proxy = Proxy::all(url)
+            .map_err(|e| e.to_string())?
+            .no_proxy(NoProxy::from_string(&self.no_proxy));
+        Ok(builder.proxy(proxy))
+    }
+
+    // The app wide proxy, unset leaves the builder to whatever the user's environment says
+    pub fn apply_global(&self, builder: ClientBuilder) -> Result<ClientBuilder, String> {
+        if self.is_set() {
+            self.apply(builder)
+        } else {
+            Ok(builder)
+        }
+    }
+}
+
+// How a single download reaches its server
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum ProxyChoice {
+    #[default]
+    Global,
+    Direct,
+    Custom(ProxySettings),
+}
+impl ProxyChoice {
+    pub fn apply(
+        &self,
+        builder: ClientBuilder,
+        global: &ProxySettings,
+    ) -> Result<ClientBuilder, String> {
+        match self {
+            ProxyChoice::Global => global.apply_global(builder),
+            ProxyChoice::Direct => Ok(builder.no_proxy()),
+            ProxyChoice::Custom(proxy) => proxy.apply(builder),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ProxyChoice::Global => "Global".to_string(),
+            ProxyChoice::Direct => "Direct".to_string(),
+            ProxyChoice::Custom(proxy) => proxy.address().unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{fs, path::Path};
+
+use dl::file2dl::File2Dl;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{config_path, write_config_file, Settings},
+    metadata::save_metadata,
+    Core, MyApp,
+};
+
+const QUEUE_FILE: &str = "queue.json";
+pub const DEFAULT_MAX_ACTIVE: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct QueueFile {
+    max_active: usize,
+    order: Vec<String>,
+    queued: Vec<String>,
+}
+
+pub struct Queue {
+    pub max_active: usize,
+    saved: Option<QueueFile>,
+}
+impl Default for Queue {
+    fn default() -> Self {
+        Self {
+            max_active: DEFAULT_MAX_ACTIVE,
+            saved: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMove {
+    Up,
+    Down,
+    Top,
+}
+
+pub fn file_key(file: &File2Dl) -> String {
+    Path::new(&file.dir)
+        .join(&file.name_on_disk)
+        .to_string_lossy()
+        .to_string()
+}
+
+fn queue_key(core: &Core) -> String {
+    file_key(&core.file)
+}
+
+fn read_queue_file() -> Option<QueueFile> {
+    let content = fs::read_to_string(config_path(QUEUE_FILE)).ok()?;
+    serde_json::from_str::<QueueFile>(&content).ok()
+}
+
+fn write_queue_file(queue: &QueueFile) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(queue).map_err(|e| e.to_string())?;
+    write_config_file(QUEUE_FILE, &json)
+}
+
+// Restores the saved order and queued flags, cores missing from the file keep their place at the end
+pub fn load_queue(cores: &mut [Core]) -> Queue {
+    let saved = match read_queue_file() {
+        Some(saved) => saved,
+        None => return Queue::default(),
+    };
+    cores.sort_by_key(|core| {
+        let key = queue_key(core);
+        saved
+            .order
+            .iter()
+            .position(|k| *k == key)
+            .unwrap_or(usize::MAX)
+    });
+    for core in cores.iter_mut() {
+        core.queued = saved.queued.contains(&queue_key(core));
+    }
+    Queue {
+        max_active: saved.max_active.max(1),
+        saved: Some(saved),
+    }
+}
+
+// Puts back in the queue what was running before the app closed or crashed, the rest stays paused
+pub fn resume_on_startup(cores: &mut [Core], settings: &Settings) {
+    for core in cores.iter_mut() {
+        let resume = core
+            .metadata
+            .resume_on_startup
+            .unwrap_or(settings.resume_on_startup);
+        // Off only means a crash is not made up for, the saved queue stays as it was
+        if resume && core.metadata.running {
+            enqueue(core);
+        }
+    }
+}
+
+// Keeps the running flag of every download current, paused by the schedule or a lost connection still counts as running
+pub fn process_running_state(app: &mut MyApp) {
+    for core in app.inner.iter_mut() {
+        let key = file_key(&core.file);
+        let running = core.queued
+            || is_active(core)
+            || app.scheduler.holds(&key)
+            || app
+                .connected_to_net
+                .paused
+                .iter()
+                .any(|(paused, _)| *paused == key);
+        if running == core.metadata.running {
+            continue;
+        }
+        core.metadata.running = running;
+        if let Err(e) = save_metadata(&core.file, &core.metadata) {
+            app.popus.error.value = e;
+            app.popus.error.show = true;
+        }
+    }
+}
+
+// What the schedule paused is saved as queued, a restart inside the pause window pauses it again
+fn save_queue(app: &mut MyApp) {
+    let current = QueueFile {
+        max_active: app.queue.max_active,
+        order: app.inner.iter().map(queue_key).collect(),
+        queued: app
+            .inner
+            .iter()
+            // Held by the schedule or a lost connection, they are queued again after a restart
+            .filter(|core| {
+                let key = queue_key(core);
+                core.queued
+                    || app.scheduler.holds(&key)
+                    || app
+                        .connected_to_net
+                        .paused
+                        .iter()
+                        .any(|(paused, _)| *paused == key)
+            })
+            .map(queue_key)
+            .collect(),
+    };
+    if app.queue.saved.as_ref() == Some(&current) {
+        return;
+    }
+    if let Err(e) = write_queue_file(&current) {
+        app.popus.error.value = e;
+        app.popus.error.show = true;
+    }
+    app.queue.saved = Some(current);
+}
+
+pub fn saved_queued() -> Vec<String> {
+    read_queue_file()
+        .map(|saved| saved.queued)
+        .unwrap_or_default()
+}
+
+// Edits the saved queue directly, used when no app instance owns it
+pub fn set_saved_queued(key: &str, queued: bool) -> Result<(), String> {
+    let mut saved = read_queue_file().unwrap_or(QueueFile {
+        max_active: DEFAULT_MAX_ACTIVE,
+        order: Vec::new(),
+        queued: Vec::new(),
+    });
+    if !saved.order.iter().any(|k| k == key) {
+        saved.order.push(key.to_string());
+    }
+    saved.queued.retain(|k| k != key);
+    if queued {
+        saved.queued.push(key.to_string());
+    }
+    write_queue_file(&saved)
+}
+
+pub fn remove_saved(key: &str) -> Result<(), String> {
+    let mut saved = match read_queue_file() {
+        Some(saved) => saved,
+        None => return Ok(()),
+    };
+    saved.order.retain(|k| k != key);
+    saved.queued.retain(|k| k != key);
+    write_queue_file(&saved)
+}
+
+pub fn is_active(core: &Core) -> bool {
+    *core.file.status.1.borrow()
+        && !core
+            .file
+            .complete
+            .load(std::sync::atomic::Ordering::Relaxed)
+}
+
+pub fn enqueue(core: &mut Core) {
+    let done = core
+        .file
+        .complete
+        .load(std::sync::atomic::Ordering::Relaxed);
+    if !done && !*core.file.status.1.borrow() {
+        core.queued = true;
+    }
+}
+
+pub fn queue_position(app: &MyApp, index: usize) -> Option<usize> {
+    if !app.inner.get(index)?.queued {
+        return None;
+    }
+    Some(app.inner[..index].iter().filter(|core| core.queued).count() + 1)
+}
+
+pub fn move_in_queue(app: &mut MyApp, index: usize, direction: QueueMove) {
+    if index >= app.inner.len() {
+        return;
+    }
+    match direction {
+        QueueMove::Up if index > 0 => app.inner.swap(index, index - 1),
+        QueueMove::Down if index + 1 < app.inner.len() => app.inner.swap(index, index + 1),
+        QueueMove::Top => {
+            let core = app.inner.remove(index);
+            app.inner.insert(0, core);
+        }
+        _ => {}
+    }
+}
+
+// Starts queued downloads in list order until the active limit is reached
+pub fn process_queue(app: &mut MyApp) {
+    for core in app.inner.iter_mut() {
+        let done = core
+            .file
+            .complete
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if core.queued && (done || *core.file.status.1.borrow()) {
+            core.queued = false;
+        }
+    }
+    // Whatever gets queued during a scheduled pause waits for its end
+    if app.scheduler.pauses_all() {
+        save_queue(app);
+        return;
+    }
+    let mut active = app.inner.iter().filter(|core| is_active(core)).count();
+    for core in app.inner.iter_mut() {
+        if active >= app.queue.max_active {
+            break;
+        }
+        if core.queued {
+            core.file.status.0.send(true).unwrap();
+            core.queued = false;
+            active += 1;
+        }
+    }
+    save_queue(app);
+}
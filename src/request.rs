@@ -0,0 +1,385 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    },
+};
+
+use dl::{file2dl::File2Dl, url::Url};
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_RANGE, CONTENT_TYPE,
+        COOKIE, RANGE, USER_AGENT,
+    },
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{
+    bandwidth::mbs_to_bytes,
+    credentials::Credential,
+    errors::DownloadError,
+    history::now,
+    proxy::{ProxyChoice, ProxySettings},
+    resolve::{engine_file, free_name, NewFile, RESOLVE_TIMEOUT},
+};
+
+// How every request of a download is made, the engine cannot do any of it so these downloads go through the segment downloader
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestOptions {
+    pub headers: Vec<(String, String)>,
+    // As sent in the Cookie header, "name=value; other=value"
+    pub cookies: String,
+    pub user_agent: String,
+    pub proxy: ProxyChoice,
+}
+
+impl RequestOptions {
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+            && self.cookies.is_empty()
+            && self.user_agent.is_empty()
+            && self.proxy == ProxyChoice::Global
+    }
+
+    pub fn header_map(&self) -> Result<HeaderMap, String> {
+        let mut map = HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid header name: {}", name))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|_| format!("Invalid value for header {}", name))?;
+            map.append(name, value);
+        }
+        if !self.cookies.is_empty() {
+            let value = HeaderValue::from_str(&self.cookies)
+                .map_err(|_| "Invalid cookie string".to_string())?;
+            map.insert(COOKIE, value);
+        }
+        if !self.user_agent.is_empty() {
+            let value = HeaderValue::from_str(&self.user_agent)
+                .map_err(|_| "Invalid User-Agent".to_string())?;
+            map.insert(USER_AGENT, value);
+        }
+        Ok(map)
+    }
+
+    // global is the proxy of the settings, used unless the download chose its own
+    pub fn client_builder(&self, global: &ProxySettings) -> Result<reqwest::ClientBuilder, String> {
+        let builder = reqwest::Client::builder().default_headers(self.header_map()?);
+        self.proxy.apply(builder, global)
+    }
+
+    pub fn client(&self, global: &ProxySettings) -> Result<reqwest::Client, String> {
+        self.client_builder(global)?
+            .connect_timeout(RESOLVE_TIMEOUT)
+            .build()
+            .map_err(|e| e.to_string())
+    }
+}
+
+// One "Name: value" per line
+pub fn parse_headers(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut headers = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("Expected \"Name: value\", got {}", line))?;
+        let name = name.trim();
+        let value = value.trim();
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name: {}", name))?;
+        HeaderValue::from_str(value).map_err(|_| format!("Invalid value for header {}", name))?;
+        headers.push((name.to_string(), value.to_string()));
+    }
+    Ok(headers)
+}
+
+pub fn format_headers(headers: &[(String, String)]) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Picks the cookies of a Netscape cookies.txt that the url would be sent, expired ones are left out
+pub fn cookies_from_netscape(content: &str, link: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(link.trim()).map_err(|_| format!("Invalid URL: {}", link))?;
+    let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+    let secure = url.scheme() == "https";
+    let now = now();
+    let mut cookies = Vec::new();
+    for line in content.lines() {
+        // curl marks http only cookies this way, they are still cookies
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let fields = line.split('\t').collect::<Vec<&str>>();
+        if fields.len() != 7 {
+            continue;
+        }
+        let domain = fields[0].to_ascii_lowercase();
+        let subdomains = fields[1].eq_ignore_ascii_case("TRUE") || domain.starts_with('.');
+        let domain = domain.trim_start_matches('.');
+        let matches_domain =
+            host == domain || (subdomains && host.ends_with(&format!(".{}", domain)));
+        let matches_path = url.path().starts_with(fields[2]);
+        let allowed = secure || !fields[3].eq_ignore_ascii_case("TRUE");
+        let expiry = fields[4].parse::<u64>().unwrap_or_default();
+        let alive = expiry == 0 || expiry > now;
+        if matches_domain && matches_path && allowed && alive {
+            cookies.push(format!("{}={}", fields[5], fields[6].trim_end()));
+        }
+    }
+    if cookies.is_empty() {
+        return Err(format!("No cookie in the file applies to {}", host));
+    }
+    Ok(cookies.join("; "))
+}
+
+struct Probe {
+    total_size: usize,
+    content_type: String,
+    filename: Option<String>,
+}
+
+// Asks the server what File2Dl::new would, but with the headers and credentials it wants
+async fn probe(
+    link: &str,
+    request: &RequestOptions,
+    credential: &Option<Credential>,
+    proxy: &ProxySettings,
+) -> Result<Probe, DownloadError> {
+    let client = request.client(proxy).map_err(DownloadError::Other)?;
+    let mut builder = client.get(link).header(RANGE, "bytes=0-0");
+    if let Some(credential) = credential {
+        builder = credential.apply(builder);
+    }
+    let response = tokio::time::timeout(RESOLVE_TIMEOUT, builder.send())
+        .await
+        .map_err(|_| {
+            DownloadError::Timeout(format!(
+                "{} did not answer within {}s",
+                link,
+                RESOLVE_TIMEOUT.as_secs()
+            ))
+        })?
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| DownloadError::from_error(&e))?;
+    // The segment downloader fetches nothing but ranges
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::Other(format!(
+            "{} does not support ranges, which a download with its own headers or credentials needs",
+            link
+        )));
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let total_size = header(CONTENT_RANGE)
+        .and_then(|range| range.rsplit('/').next().map(str::to_string))
+        .and_then(|size| size.parse::<usize>().ok())
+        .ok_or_else(|| {
+            DownloadError::Other(format!("{} did not tell the size of the file", link))
+        })?;
+    let filename = header(CONTENT_DISPOSITION)
+        .and_then(|value| content_disposition::parse_content_disposition(&value).filename_full())
+        .and_then(|name| {
+            Path::new(&name)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        });
+    Ok(Probe {
+        total_size,
+        content_type: header(CONTENT_TYPE).unwrap_or_default(),
+        filename,
+    })
+}
+
+// The last path segment of the url, decoded
+fn name_from_link(link: &str) -> Option<String> {
+    let url = reqwest::Url::parse(link).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    let name = percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .to_string();
+    Path::new(&name)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+}
+
+// A file the engine never saw, it only ever goes through the segment downloader
+pub fn own_file(
+    link: &str,
+    dir: &str,
+    name: &str,
+    total_size: usize,
+    content_type: &str,
+    bandwidth: usize,
+) -> File2Dl {
+    File2Dl {
+        url: Url {
+            link: link.to_string(),
+            filename: name.to_string(),
+            total_size,
+            range_support: true,
+            content_type: content_type.to_string(),
+        },
+        size_on_disk: Arc::new(AtomicUsize::new(0)),
+        status: watch::channel(false),
+        name_on_disk: name.to_string(),
+        dir: dir.to_string(),
+        complete: Arc::new(AtomicBool::new(false)),
+        bandwidth_chosen: Arc::new(AtomicUsize::new(bandwidth)),
+        transfer_rate: Arc::new(AtomicUsize::new(0)),
+    }
+}
+
+// The engine cannot send headers or credentials and never goes through a proxy,
+// so such a file is built from our own request instead
+pub async fn fetch_file(
+    link: &str,
+    dir: &str,
+    bandwidth: f64,
+    request: &RequestOptions,
+    credential: &Option<Credential>,
+    proxy: &ProxySettings,
+) -> Result<NewFile, DownloadError> {
+    if request.is_empty() && credential.is_none() && !proxy.is_set() {
+        return engine_file(link, dir, bandwidth).await.map(NewFile::from);
+    }
+    let probe = probe(link, request, credential, proxy).await?;
+    let name = probe
+        .filename
+        .or_else(|| name_from_link(link))
+        .filter(|name| !name.is_empty() && name != "." && name != "..")
+        .unwrap_or_else(|| "download".to_string());
+    let name = free_name(dir, &name, &[]);
+    let file = own_file(
+        link,
+        dir,
+        &name,
+        probe.total_size,
+        &probe.content_type,
+        mbs_to_bytes(bandwidth),
+    );
+    Ok(NewFile {
+        probed: true,
+        ..file.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOKIES: &str = "# Netscape HTTP Cookie File
+.example.com\tTRUE\t/\tFALSE\t0\tsession\tabc
+#HttpOnly_www.example.com\tFALSE\t/\tFALSE\t0\ttoken\txyz
+www.example.com\tFALSE\t/private\tFALSE\t0\tprivate\t1
+www.example.com\tFALSE\t/\tTRUE\t0\tsecure\t2
+www.example.com\tFALSE\t/\tFALSE\t1\texpired\t3
+other.com\tTRUE\t/\tFALSE\t0\tforeign\t4
+";
+
+    #[test]
+    fn cookies_follow_domain_path_and_scheme() {
+        assert_eq!(
+            cookies_from_netscape(COOKIES, "http://www.example.com/file.zip").unwrap(),
+            "session=abc; token=xyz"
+        );
+        assert_eq!(
+            cookies_from_netscape(COOKIES, "https://www.example.com/private/file.zip").unwrap(),
+            "session=abc; token=xyz; private=1; secure=2"
+        );
+    }
+
+    #[test]
+    fn subdomain_cookies_need_the_flag() {
+        assert_eq!(
+            cookies_from_netscape(COOKIES, "http://cdn.example.com/file.zip").unwrap(),
+            "session=abc"
+        );
+    }
+
+    #[test]
+    fn no_matching_cookie_is_an_error() {
+        assert!(cookies_from_netscape(COOKIES, "http://example.org/file.zip").is_err());
+        assert!(cookies_from_netscape(COOKIES, "not a url").is_err());
+    }
+
+    // Answers 401 until a request carries basic auth, then a range of a 1000 byte file
+    async fn protected_server() -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = vec![0; 4096];
+                let read = stream.read(&mut buffer).await.unwrap_or_default();
+                let head = String::from_utf8_lossy(&buffer[..read]).to_ascii_lowercase();
+                let response = if head.contains("\r\nauthorization: basic ") {
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-0/1000\r\nContent-Length: 1\r\nConnection: close\r\n\r\nx"
+                } else {
+                    "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/protected/file.bin", address)
+    }
+
+    #[tokio::test]
+    async fn unauthorized_host_resolves_once_credentials_are_saved() {
+        let link = protected_server().await;
+        let dir = std::env::temp_dir().to_string_lossy().to_string();
+        let request = RequestOptions {
+            proxy: ProxyChoice::Direct,
+            ..Default::default()
+        };
+        let mut credentials = crate::credentials::Credentials::default();
+        let proxy = ProxySettings::default();
+        let error = fetch_file(
+            &link,
+            &dir,
+            0.0,
+            &request,
+            &credentials.for_link(&link),
+            &proxy,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(error.is_unauthorized());
+        credentials.hosts.insert(
+            crate::credentials::host_of(&link).unwrap(),
+            Credential::Basic {
+                username: "user".to_string(),
+                password: "secret".to_string(),
+            },
+        );
+        let new = fetch_file(
+            &link,
+            &dir,
+            0.0,
+            &request,
+            &credentials.for_link(&link),
+            &proxy,
+        )
+        .await
+        .unwrap();
+        assert!(new.probed);
+        assert_eq!(new.file.url.total_size, 1000);
+        assert_eq!(new.file.url.link, link);
+        assert!(new.file.name_on_disk.starts_with("file"));
+    }
+}
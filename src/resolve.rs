@@ -0,0 +1,338 @@
+use std::{
+    future::Future,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use dl::file2dl::File2Dl;
+use tokio::sync::{oneshot, Semaphore};
+
+use crate::{
+    checksum::Checksum, checksum_discovery::spawn_discovery, errors::DownloadError,
+    metadata::save_metadata, request::RequestOptions, Core, MyApp, Threading,
+};
+
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(30);
+// A whole row, a metalink resolves each of its files in turn
+const JOB_TIMEOUT: Duration = Duration::from_secs(120);
+// Rows resolving at once, a long pattern or batch waits its turn instead of opening hundreds of connections
+const RESOLVE_WORKERS: usize = 6;
+
+// A download ready to be added, with whatever its source knew about it
+pub struct NewFile {
+    pub file: File2Dl,
+    pub mirrors: Vec<String>,
+    pub checksum: Option<Checksum>,
+    // Built from our own request, the engine has no metadata of it
+    pub probed: bool,
+}
+impl From<File2Dl> for NewFile {
+    fn from(file: File2Dl) -> Self {
+        Self {
+            file,
+            mirrors: Vec::new(),
+            checksum: None,
+            probed: false,
+        }
+    }
+}
+
+// Everything a resolving row produced, files added over rpc have no row
+pub struct Resolved {
+    pub id: Option<u64>,
+    pub files: Vec<NewFile>,
+    pub failures: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AddOptions {
+    pub threading: Threading,
+    pub threads: usize,
+    pub start_at: Option<u64>,
+    pub stop_at: Option<u64>,
+    pub discover_checksum: bool,
+    pub request: RequestOptions,
+}
+
+// Where failures are reported once the row is done
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Dialog,
+    Pattern,
+    Remote,
+    // The line as typed, put back in the batch window if it fails
+    Batch { number: usize, line: String },
+}
+
+pub struct Resolving {
+    pub id: u64,
+    pub label: String,
+    pub started: Instant,
+    pub origin: Origin,
+    options: AddOptions,
+    // Dropping the row drops the sender, which cancels the request
+    _cancel: oneshot::Sender<()>,
+}
+
+pub struct Resolver {
+    pub rows: Vec<Resolving>,
+    next_id: u64,
+    permits: Arc<Semaphore>,
+}
+impl Default for Resolver {
+    fn default() -> Self {
+        Self {
+            rows: Vec::new(),
+            next_id: 0,
+            permits: Arc::new(Semaphore::new(RESOLVE_WORKERS)),
+        }
+    }
+}
+
+pub async fn engine_file(link: &str, dir: &str, bandwidth: f64) -> Result<File2Dl, DownloadError> {
+    match tokio::time::timeout(RESOLVE_TIMEOUT, File2Dl::new(link, dir, bandwidth)).await {
+        Ok(Ok(file)) => Ok(file),
+        Ok(Err(e)) => Err(DownloadError::from_error(&e)),
+        Err(_) => Err(DownloadError::Timeout(format!(
+            "{} did not answer within {}s",
+            link,
+            RESOLVE_TIMEOUT.as_secs()
+        ))),
+    }
+}
+
+// Runs the job on the shared runtime, the row shows up in the list until it is done
+pub fn spawn_resolve<F, Fut>(
+    app: &mut MyApp,
+    label: String,
+    origin: Origin,
+    options: AddOptions,
+    job: F,
+) -> u64
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = (Vec<NewFile>, Vec<String>)>,
+{
+    let id = app.resolver.next_id;
+    app.resolver.next_id += 1;
+    let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+    let tx = app.file_channel.0.clone();
+    let name = label.clone();
+    let permits = app.resolver.permits.clone();
+    app.supervisor.run(cancel_rx, move || async move {
+        // Waiting for a turn does not count towards the timeout
+        let _permit = permits.acquire_owned().await;
+        let (files, failures) = match tokio::time::timeout(JOB_TIMEOUT, job()).await {
+            Ok(resolved) => resolved,
+            Err(_) => (
+                Vec::new(),
+                vec![format!(
+                    "{}: did not resolve within {}s",
+                    name,
+                    JOB_TIMEOUT.as_secs()
+                )],
+            ),
+        };
+        let _ = tx.send(Resolved {
+            id: Some(id),
+            files,
+            failures,
+        });
+    });
+    app.resolver.rows.push(Resolving {
+        id,
+        label,
+        started: Instant::now(),
+        origin,
+        options,
+        _cancel: cancel_tx,
+    });
+    id
+}
+
+pub fn cancel_resolve(app: &mut MyApp, id: u64) {
+    let index = match app.resolver.rows.iter().position(|row| row.id == id) {
+        Some(index) => index,
+        None => return,
+    };
+    let row = app.resolver.rows.remove(index);
+    if app.popus.download.waiting == Some(id) {
+        app.popus.download.waiting = None;
+    }
+    if let Origin::Batch { number, line } = row.origin {
+        app.popus
+            .batch
+            .failures
+            .push(format!("Line {}: cancelled", number));
+        app.popus.batch.failed_lines.push(line);
+        finish_batch_line(app, id);
+    }
+}
+
+pub fn already_added(app: &MyApp, file: &File2Dl) -> bool {
+    app.inner.iter().any(|core| {
+        core.file.url.link == file.url.link
+            && core.file.size_on_disk.load(Ordering::Relaxed) < core.file.url.total_size
+    })
+}
+
+// Names of the downloads in the list that live in dir
+pub fn names_in_dir(app: &MyApp, dir: &str) -> Vec<String> {
+    app.inner
+        .iter()
+        .filter(|core| Path::new(&core.file.dir) == Path::new(dir))
+        .map(|core| core.file.name_on_disk.clone())
+        .collect()
+}
+
+// The name itself when neither a download nor anything on disk uses it, otherwise "name (2).ext" and so on
+pub fn free_name(dir: &str, name: &str, taken: &[String]) -> String {
+    let is_free = |candidate: &str| {
+        let dir = Path::new(dir);
+        !taken.iter().any(|taken| taken == candidate)
+            && !dir.join(candidate).exists()
+            && !dir.join(format!(".{}", candidate)).exists()
+    };
+    if is_free(name) {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::default()),
+    };
+    (2..)
+        .map(|number| format!("{} ({}){}", stem, number, extension))
+        .find(|candidate| is_free(candidate))
+        .unwrap_or_default()
+}
+
+fn add_core(app: &mut MyApp, mut new: NewFile, options: &AddOptions) -> Result<(), String> {
+    if already_added(app, &new.file) {
+        return Err(format!(
+            "{}: Download already exists,simply resume it",
+            new.file.url.link
+        ));
+    }
+    // Files resolved side by side only learn about each other here
+    let taken = names_in_dir(app, &new.file.dir);
+    if taken.contains(&new.file.name_on_disk) {
+        let name = free_name(&new.file.dir, &new.file.name_on_disk, &taken);
+        new.file.name_on_disk = name.clone();
+        new.file.url.filename = name;
+    }
+    let mut core = Core::new(new.file, options.threading.clone(), options.threads);
+    // A scheduled download waits for its start time instead of the queue
+    core.queued = options.start_at.is_none();
+    core.metadata.start_at = options.start_at;
+    core.metadata.stop_at = options.stop_at;
+    core.metadata.mirrors = new.mirrors;
+    core.metadata.request = options.request.clone();
+    core.metadata.authenticated = app.credentials.for_link(&core.file.url.link).is_some();
+    if new.probed {
+        core.metadata.link = core.file.url.link.clone();
+        core.metadata.content_type = core.file.url.content_type.clone();
+    }
+    if core.metadata.segmented() {
+        core.metadata.total_size = Some(core.file.url.total_size);
+    }
+    if new.checksum.is_none() && options.discover_checksum {
+        core.discovery = Some(spawn_discovery(
+            &app.supervisor.handle(),
+            core.file.url.link.clone(),
+            core.file.name_on_disk.clone(),
+            core.metadata.request.clone(),
+            app.credentials.for_link(&core.file.url.link),
+            app.settings.proxy.clone(),
+        ));
+    }
+    core.metadata.checksum = new.checksum;
+    let saved = save_metadata(&core.file, &core.metadata);
+    app.inner.push(core);
+    saved
+}
+
+fn finish_batch_line(app: &mut MyApp, id: u64) {
+    let batch = &mut app.popus.batch;
+    if !batch.waiting.contains(&id) {
+        return;
+    }
+    batch.waiting.retain(|waiting| *waiting != id);
+    if !batch.waiting.is_empty() {
+        return;
+    }
+    if batch.failures.is_empty() {
+        batch.show = false;
+        batch.save_to = String::default();
+    } else {
+        batch.error = format!(
+            "Added {} of {}, these lines failed:\n{}",
+            batch.total - batch.failures.len(),
+            batch.total,
+            batch.failures.join("\n")
+        );
+        batch.text = batch.failed_lines.join("\n");
+    }
+}
+
+pub fn process_resolved(app: &mut MyApp) {
+    while let Ok(resolved) = app.file_channel.1.try_recv() {
+        let (origin, options) = match resolved.id {
+            Some(id) => match app.resolver.rows.iter().position(|row| row.id == id) {
+                Some(index) => {
+                    let row = app.resolver.rows.remove(index);
+                    (row.origin, row.options)
+                }
+                // Cancelled while the answer was on its way
+                None => continue,
+            },
+            None => {
+                let threads = app.settings.default_threads;
+                let options = AddOptions {
+                    threading: Threading::for_threads(threads),
+                    threads,
+                    start_at: None,
+                    stop_at: None,
+                    discover_checksum: false,
+                    request: RequestOptions::default(),
+                };
+                (Origin::Remote, options)
+            }
+        };
+        let mut failures = resolved.failures;
+        for new in resolved.files {
+            if let Err(e) = add_core(app, new, &options) {
+                failures.push(e);
+            }
+        }
+        match (origin, resolved.id) {
+            (Origin::Dialog, Some(id)) if app.popus.download.waiting == Some(id) => {
+                app.popus.download.waiting = None;
+                if failures.is_empty() {
+                    app.popus.download.reset();
+                } else {
+                    app.popus.download.error = failures.join("\n");
+                }
+            }
+            (Origin::Batch { number, line }, Some(id)) => {
+                let batch = &mut app.popus.batch;
+                if let Some(failure) = failures.first() {
+                    batch.failures.push(format!("Line {}: {}", number, failure));
+                    batch.failed_lines.push(line);
+                }
+                finish_batch_line(app, id);
+            }
+            _ => {
+                if !failures.is_empty() {
+                    if app.popus.error.show {
+                        app.popus.error.value.push('\n');
+                    } else {
+                        app.popus.error.value = String::default();
+                    }
+                    app.popus.error.value.push_str(&failures.join("\n"));
+                    app.popus.error.show = true;
+                }
+            }
+        }
+    }
+}
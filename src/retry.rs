@@ -0,0 +1,382 @@
+use std::{
+    error::Error,
+    future::Future,
+    io::ErrorKind,
+    pin::Pin,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use dl::file2dl::Download;
+use eframe::egui::mutex::Mutex;
+use reqwest::{header::RANGE, StatusCode};
+
+use crate::{
+    bandwidth::TokenBucket,
+    config::Settings,
+    credentials::{host_of, request_credentials, Credentials},
+    errors::DownloadError,
+    metadata::save_metadata,
+    mirrors::{mirror_dl, mirror_job},
+    proxy::ProxySettings,
+    queue::{enqueue, file_key},
+    request::RequestOptions,
+    resolve::RESOLVE_TIMEOUT,
+    Core, MyApp, Threading,
+};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+// Not Send, see Supervisor::run
+pub type BoxedTask = Pin<Box<dyn Future<Output = ()>>>;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    Network,
+    Timeout,
+    HttpStatus(u16),
+    Disk,
+    Permission,
+    Other,
+}
+impl FailureKind {
+    // 4xx and disk problems will not fix themselves, everything transient is worth another try
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FailureKind::Network | FailureKind::Timeout => true,
+            FailureKind::HttpStatus(status) => *status >= 500 || *status == 408 || *status == 429,
+            FailureKind::Disk | FailureKind::Permission | FailureKind::Other => false,
+        }
+    }
+}
+
+// Walks the source chain looking for the reqwest or io error underneath the engine's error
+pub fn classify(error: &(dyn Error + 'static)) -> FailureKind {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            if let Some(status) = e.status() {
+                return FailureKind::HttpStatus(status.as_u16());
+            }
+            if e.is_timeout() {
+                return FailureKind::Timeout;
+            }
+            if e.is_connect() || e.is_request() || e.is_body() || e.is_decode() {
+                return FailureKind::Network;
+            }
+        }
+        if let Some(e) = e.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                ErrorKind::TimedOut => FailureKind::Timeout,
+                ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::NotConnected
+                | ErrorKind::BrokenPipe
+                | ErrorKind::UnexpectedEof => FailureKind::Network,
+                ErrorKind::PermissionDenied => FailureKind::Permission,
+                _ => FailureKind::Disk,
+            };
+        }
+        source = e.source();
+    }
+    FailureKind::Other
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+}
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_attempts: settings.max_attempts,
+        }
+    }
+
+    // Exponential backoff where the second half of the delay is random so downloads do not retry in lockstep
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = exponential.min(MAX_DELAY);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.subsec_nanos())
+            .unwrap_or_default();
+        let jitter = delay.mul_f64((nanos / 1000 % 1000) as f64 / 1000.0) / 2;
+        delay / 2 + jitter
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+    Retrying {
+        attempt: u32,
+        max_attempts: u32,
+        delay: Duration,
+        error: DownloadError,
+    },
+    Failed(DownloadError),
+    ChunkDone(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryStatus {
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub until: Instant,
+    pub error: DownloadError,
+}
+
+// Resuming from a server that ignores ranges would silently restart or corrupt the file, asked the way the download itself asks
+async fn supports_ranges(
+    request: &RequestOptions,
+    proxy: &ProxySettings,
+    link: &str,
+    offset: usize,
+) -> Option<bool> {
+    let client = request
+        .client_builder(proxy)
+        .ok()?
+        .connect_timeout(RESOLVE_TIMEOUT)
+        .build()
+        .ok()?;
+    let response = client
+        .get(link)
+        .header(RANGE, format!("bytes={}-", offset))
+        .send()
+        .await
+        .ok()?;
+    match response.status() {
+        StatusCode::PARTIAL_CONTENT => Some(true),
+        StatusCode::OK => Some(false),
+        _ => None,
+    }
+}
+
+// Returns the job the supervisor runs, the engine's future is only created on the thread that drives it
+pub fn download_task(
+    core: &Core,
+    credentials: &Credentials,
+    policy: RetryPolicy,
+    connected: Arc<Mutex<bool>>,
+    proxy: &ProxySettings,
+    bucket: &Arc<TokenBucket>,
+) -> impl FnOnce() -> BoxedTask + Send + 'static {
+    let mut file = core.file.clone();
+    let threads = core.threads;
+    let mirrors = mirror_job(core, credentials, proxy, bucket);
+    let proxy = proxy.clone();
+    let request = core.metadata.request.clone();
+    let tx = core.channel.0.clone();
+    let single = mirrors.is_none()
+        && core.threading == Threading::Single
+        && std::path::Path::new(&file.dir)
+            .join(&file.name_on_disk)
+            .is_file();
+    move || {
+        Box::pin(async move {
+            let mut attempt = 0;
+            let mut progress = file.size_on_disk.load(Ordering::Relaxed);
+            if single && progress > 0 && progress < file.url.total_size {
+                if let Some(false) =
+                    supports_ranges(&request, &proxy, &file.url.link, progress).await
+                {
+                    let _ = file.status.0.send(false);
+                    let _ = tx.send(DownloadEvent::Failed(DownloadError::RangesUnsupported));
+                    return;
+                }
+            }
+            loop {
+                let result = if let Some(job) = &mirrors {
+                    mirror_dl(&file, threads, job, &tx).await
+                } else if single {
+                    file.single_thread_dl()
+                        .await
+                        .map_err(|e| DownloadError::from_error(&e))
+                } else {
+                    file.multi_thread_dl(threads)
+                        .await
+                        .map_err(|e| DownloadError::from_error(&e))
+                };
+                let error = match result {
+                    Ok(_) => break,
+                    Err(error) => error,
+                };
+                // Attempts only count while we are online and not making progress
+                if !*connected.lock() && error.is_retryable() {
+                    tokio::time::sleep(BASE_DELAY).await;
+                    continue;
+                }
+                let current = file.size_on_disk.load(Ordering::Relaxed);
+                if current > progress {
+                    attempt = 0;
+                    progress = current;
+                }
+                attempt += 1;
+                if !error.is_retryable() || attempt >= policy.max_attempts {
+                    let _ = file.status.0.send(false);
+                    let _ = tx.send(DownloadEvent::Failed(error));
+                    break;
+                }
+                let delay = policy.delay(attempt);
+                let _ = tx.send(DownloadEvent::Retrying {
+                    attempt,
+                    max_attempts: policy.max_attempts,
+                    delay,
+                    error,
+                });
+                tokio::time::sleep(delay).await;
+            }
+        })
+    }
+}
+
+pub fn retry_download(core: &mut Core) {
+    core.error = None;
+    enqueue(core);
+}
+
+pub fn process_download_events(app: &mut MyApp) {
+    let mut unauthorized = Vec::new();
+    for core in app.inner.iter_mut() {
+        let mut chunks = false;
+        while let Ok(event) = core.channel.1.try_recv() {
+            match event {
+                DownloadEvent::Retrying {
+                    attempt,
+                    max_attempts,
+                    delay,
+                    error,
+                } => {
+                    core.retry = Some(RetryStatus {
+                        attempt,
+                        max_attempts,
+                        until: Instant::now() + delay,
+                        error,
+                    });
+                }
+                DownloadEvent::ChunkDone(index) => {
+                    core.metadata.mirror_chunks.push(index);
+                    chunks = true;
+                }
+                DownloadEvent::Failed(error) => {
+                    // Kept until dismissed or retried, the next resume spawns a fresh download
+                    core.retry = None;
+                    core.started = false;
+                    if error.is_unauthorized() {
+                        if let Some(host) = host_of(&core.file.url.link) {
+                            unauthorized.push((host, file_key(&core.file)));
+                        }
+                    }
+                    core.error = Some(error);
+                }
+            }
+        }
+        // Saved once per frame however many chunks came in, resuming then skips them
+        if chunks {
+            if let Err(e) = save_metadata(&core.file, &core.metadata) {
+                app.popus.error.value = e;
+                app.popus.error.show = true;
+            }
+        }
+        if core
+            .retry
+            .as_ref()
+            .is_some_and(|retry| retry.until <= Instant::now())
+        {
+            core.retry = None;
+        }
+    }
+    for (host, key) in unauthorized {
+        request_credentials(app, host, Some(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Stands in for the engine's error, which only exposes the cause through source()
+    #[derive(Debug)]
+    struct Wrapped(Box<dyn Error + 'static>);
+    impl std::fmt::Display for Wrapped {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "download failed")
+        }
+    }
+    impl Error for Wrapped {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(self.0.as_ref())
+        }
+    }
+
+    fn wrapped_io(kind: ErrorKind) -> FailureKind {
+        let error = Wrapped(Box::new(Wrapped(Box::new(std::io::Error::from(kind)))));
+        classify(&error)
+    }
+
+    #[test]
+    fn io_errors_under_the_chain_are_classified() {
+        assert_eq!(wrapped_io(ErrorKind::ConnectionReset), FailureKind::Network);
+        assert_eq!(wrapped_io(ErrorKind::TimedOut), FailureKind::Timeout);
+        assert_eq!(
+            wrapped_io(ErrorKind::PermissionDenied),
+            FailureKind::Permission
+        );
+        assert_eq!(wrapped_io(ErrorKind::StorageFull), FailureKind::Disk);
+        assert!(wrapped_io(ErrorKind::UnexpectedEof).is_retryable());
+        assert!(!wrapped_io(ErrorKind::PermissionDenied).is_retryable());
+        assert_eq!(classify(&std::fmt::Error), FailureKind::Other);
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_a_retryable_network_error() {
+        // Bound then dropped, so nothing listens on the port
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let error = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("http://127.0.0.1:{}/", port))
+            .send()
+            .await
+            .unwrap_err();
+        let kind = classify(&Wrapped(Box::new(error)));
+        assert_eq!(kind, FailureKind::Network);
+        assert!(kind.is_retryable());
+    }
+
+    #[test]
+    fn only_transient_statuses_are_retried() {
+        assert!(FailureKind::HttpStatus(503).is_retryable());
+        assert!(FailureKind::HttpStatus(429).is_retryable());
+        assert!(FailureKind::HttpStatus(408).is_retryable());
+        assert!(!FailureKind::HttpStatus(404).is_retryable());
+    }
+
+    #[test]
+    fn backoff_stays_between_half_and_the_capped_delay() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..=40 {
+            let cap = BASE_DELAY
+                .saturating_mul(2u32.saturating_pow(attempt - 1))
+                .min(MAX_DELAY);
+            let delay = policy.delay(attempt);
+            assert!(delay >= cap / 2, "attempt {}: {:?}", attempt, delay);
+            assert!(delay <= cap, "attempt {}: {:?}", attempt, delay);
+        }
+        assert!(policy.delay(0) <= BASE_DELAY);
+    }
+}
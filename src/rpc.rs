@@ -0,0 +1,478 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use dl::file2dl::File2Dl;
+use serde_json::{json, Value};
+
+use crate::{
+    aria2::{dispatch_aria2, handle_aria2},
+    config::{load_settings, prepare_dir},
+    queue::{enqueue, file_key, queue_position},
+    request::fetch_file,
+    resolve::{NewFile, Resolved},
+    Core, MyApp,
+};
+
+pub const DEFAULT_RPC_PORT: u16 = 6800;
+const TOKEN_CHARSET: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// A batch of a few thousand links still fits, anything bigger is refused before it is read
+const MAX_BODY: usize = 1024 * 1024;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+pub const UNAUTHORIZED: i64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// A request that needs the download list, answered by the ui thread
+pub struct RpcCall {
+    pub method: String,
+    pub params: Value,
+    pub reply: Sender<Result<Value, RpcError>>,
+}
+
+pub struct RpcServer {
+    pub enabled: bool,
+    pub port: String,
+    pub token: String,
+    // The only page allowed to call the server from a browser, empty for none
+    pub origin: String,
+    running: Option<Arc<AtomicBool>>,
+    calls: (Sender<RpcCall>, Receiver<RpcCall>),
+}
+impl Default for RpcServer {
+    // Opt-in through the menu, or at startup by setting DL_RPC_TOKEN
+    fn default() -> Self {
+        let token = std::env::var("DL_RPC_TOKEN").unwrap_or_default();
+        let port = std::env::var("DL_RPC_PORT").unwrap_or(DEFAULT_RPC_PORT.to_string());
+        let origin = std::env::var("DL_RPC_ORIGIN").unwrap_or_default();
+        Self {
+            enabled: !token.is_empty(),
+            port,
+            token: if token.is_empty() {
+                generate_token()
+            } else {
+                token
+            },
+            origin,
+            running: None,
+            calls: channel(),
+        }
+    }
+}
+
+// Everything a connection needs to answer requests away from the ui thread
+#[derive(Clone)]
+pub struct ServerContext {
+    pub token: String,
+    pub origin: String,
+    pub calls: Sender<RpcCall>,
+    pub files: Sender<Resolved>,
+    pub runtime: tokio::runtime::Handle,
+    pub ctx: eframe::egui::Context,
+}
+
+pub fn generate_token() -> String {
+    random_string::generate(32, TOKEN_CHARSET)
+}
+
+pub fn gid(file: &File2Dl) -> String {
+    // FNV-1a, stable across restarts since it only depends on the path
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in file_key(file).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+// Starts or stops the server to match the menu toggle
+pub fn sync_rpc_server(app: &mut MyApp, ctx: &eframe::egui::Context) {
+    match (app.rpc.enabled, app.rpc.running.is_some()) {
+        (true, false) => {
+            let port = match app.rpc.port.parse::<u16>() {
+                Ok(port) => port,
+                Err(_) => {
+                    app.rpc.enabled = false;
+                    app.popus.error.value = format!("Invalid RPC port: {}", app.rpc.port);
+                    app.popus.error.show = true;
+                    return;
+                }
+            };
+            let listener = match TcpListener::bind(("127.0.0.1", port)) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    app.rpc.enabled = false;
+                    app.popus.error.value = format!("Could not start RPC server: {}", e);
+                    app.popus.error.show = true;
+                    return;
+                }
+            };
+            let running = Arc::new(AtomicBool::new(true));
+            let server = ServerContext {
+                token: app.rpc.token.clone(),
+                origin: app.rpc.origin.trim().to_string(),
+                calls: app.rpc.calls.0.clone(),
+                files: app.file_channel.0.clone(),
+                runtime: app.supervisor.handle(),
+                ctx: ctx.clone(),
+            };
+            let flag = running.clone();
+            std::thread::spawn(move || serve(listener, server, flag));
+            app.rpc.running = Some(running);
+        }
+        (false, true) => {
+            if let Some(running) = app.rpc.running.take() {
+                running.store(false, Ordering::Relaxed);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn serve(listener: TcpListener, server: ServerContext, running: Arc<AtomicBool>) {
+    // Non blocking so the loop notices when the server gets disabled
+    if listener.set_nonblocking(true).is_err() {
+        return;
+    }
+    while running.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let server = server.clone();
+                std::thread::spawn(move || {
+                    let _ = handle_connection(stream, &server);
+                });
+            }
+            Err(_) => sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, server: &ServerContext) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0;
+    let mut bearer = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse::<usize>().unwrap_or_default(),
+                "authorization" => {
+                    bearer = value.strip_prefix("Bearer ").map(|token| token.to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    if method == "OPTIONS" {
+        return write_response(&mut stream, server, "204 No Content", "");
+    }
+    if method != "POST" || !(path == "/jsonrpc" || path == "/") {
+        return write_response(&mut stream, server, "404 Not Found", "");
+    }
+    if content_length > MAX_BODY {
+        return write_response(&mut stream, server, "413 Payload Too Large", "");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let response = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(batch)) => Value::Array(
+            batch
+                .into_iter()
+                .map(|request| handle_request(request, bearer.as_deref(), server))
+                .collect(),
+        ),
+        Ok(request) => handle_request(request, bearer.as_deref(), server),
+        Err(e) => error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+    };
+    write_response(&mut stream, server, "200 OK", &response.to_string())
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    server: &ServerContext,
+    status: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    // Browsers only get through for the configured origin, extensions and scripts do not need CORS
+    let cors = if server.origin.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "Access-Control-Allow-Origin: {}\r\nVary: Origin\r\nAccess-Control-Allow-Methods: POST, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type, Authorization\r\n",
+            server.origin
+        )
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        cors,
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+fn handle_request(request: Value, bearer: Option<&str>, server: &ServerContext) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method.to_string(),
+        None => return error_response(id, RpcError::new(INVALID_REQUEST, "Missing method")),
+    };
+    let params = request.get("params").cloned().unwrap_or(json!({}));
+    // aria2 clients pass the token inside the params, so they authenticate on their own
+    if method.starts_with("aria2.") || method.starts_with("system.") {
+        return match handle_aria2(&method, params, bearer, server) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, e),
+        };
+    }
+    let token = bearer.or(params.get("token").and_then(Value::as_str));
+    if token != Some(server.token.as_str()) {
+        return error_response(id, RpcError::new(UNAUTHORIZED, "Unauthorized"));
+    }
+    let result = match method.as_str() {
+        "dl.add" => add_download(&params, server),
+        _ => call_ui(server, &method, params),
+    };
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => error_response(id, e),
+    }
+}
+
+pub fn call_ui(server: &ServerContext, method: &str, params: Value) -> Result<Value, RpcError> {
+    let (reply, response) = channel();
+    let call = RpcCall {
+        method: method.to_string(),
+        params,
+        reply,
+    };
+    server
+        .calls
+        .send(call)
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+    server.ctx.request_repaint();
+    response
+        .recv_timeout(REPLY_TIMEOUT)
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?
+}
+
+// Resolving the url can take a while, so it happens here and not on the ui thread
+fn add_download(params: &Value, server: &ServerContext) -> Result<Value, RpcError> {
+    let link = params
+        .get("url")
+        .and_then(Value::as_str)
+        .ok_or(RpcError::new(INVALID_PARAMS, "Missing url"))?;
+    let bandwidth = params.get("bandwidth").and_then(Value::as_f64);
+    let dir = params.get("dir").and_then(Value::as_str);
+    let mirrors = params
+        .get("mirrors")
+        .and_then(Value::as_array)
+        .map(|mirrors| {
+            mirrors
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|mirror| mirror.to_string())
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+    let file = add_link(link, &mirrors, bandwidth, dir, server)?;
+    Ok(json!({ "gid": gid(&file), "name": file.name_on_disk }))
+}
+
+// Returns a copy of the file that was handed to the ui
+pub fn add_link(
+    link: &str,
+    mirrors: &[String],
+    bandwidth: Option<f64>,
+    dir: Option<&str>,
+    server: &ServerContext,
+) -> Result<File2Dl, RpcError> {
+    let existing = call_ui(server, "dl.list", json!({}))?;
+    let duplicate = existing
+        .as_array()
+        .into_iter()
+        .flatten()
+        .any(|download| download["url"] == link && download["status"] != "complete");
+    if duplicate {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            "Download already exists,simply resume it",
+        ));
+    }
+    // Read from disk since the connection thread has no access to the ui state
+    let settings = load_settings();
+    let bandwidth = bandwidth.unwrap_or(settings.default_bandwidth);
+    let dir = match dir {
+        Some(dir) => dir.to_string(),
+        None => settings.download_dir,
+    };
+    let dir = prepare_dir(&dir).map_err(|e| RpcError::new(INTERNAL_ERROR, e))?;
+    let new = server
+        .runtime
+        .block_on(fetch_file(
+            link,
+            &dir,
+            bandwidth,
+            &Default::default(),
+            &None,
+            &settings.proxy,
+        ))
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+    let file = new.file.clone();
+    let resolved = Resolved {
+        id: None,
+        files: vec![NewFile {
+            mirrors: mirrors.to_vec(),
+            ..new
+        }],
+        failures: Vec::new(),
+    };
+    server
+        .files
+        .send(resolved)
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+    server.ctx.request_repaint();
+    Ok(file)
+}
+
+pub fn download_status(core: &Core, queued: bool) -> &'static str {
+    let done = core.file.complete.load(Ordering::Relaxed);
+    if core.error.is_some() {
+        "error"
+    } else if done {
+        "complete"
+    } else if *core.file.status.1.borrow() {
+        "downloading"
+    } else if queued {
+        "queued"
+    } else {
+        "paused"
+    }
+}
+
+fn download_stats(app: &MyApp, index: usize) -> Value {
+    let core = &app.inner[index];
+    let position = queue_position(app, index);
+    json!({
+        "gid": gid(&core.file),
+        "name": core.file.name_on_disk,
+        "url": core.file.url.link,
+        "dir": core.file.dir,
+        "status": download_status(core, position.is_some()),
+        "queue_position": position,
+        "size_on_disk": core.file.size_on_disk.load(Ordering::Relaxed),
+        "total_size": core.file.url.total_size,
+        "transfer_rate": core.file.transfer_rate.load(Ordering::Relaxed),
+        "bandwidth_chosen": core.file.bandwidth_chosen.load(Ordering::Relaxed),
+        "threads": core.threads,
+        "error": core.error.as_ref().map(|error| error.to_string()),
+    })
+}
+
+pub fn find_by_gid(app: &MyApp, gid_param: &str) -> Option<usize> {
+    app.inner
+        .iter()
+        .position(|core| gid(&core.file) == gid_param)
+}
+
+fn gid_param(app: &MyApp, params: &Value) -> Result<usize, RpcError> {
+    let gid = params
+        .get("gid")
+        .and_then(Value::as_str)
+        .ok_or(RpcError::new(INVALID_PARAMS, "Missing gid"))?;
+    find_by_gid(app, gid).ok_or(RpcError::new(
+        INVALID_PARAMS,
+        format!("No download with gid {}", gid),
+    ))
+}
+
+fn dispatch(app: &mut MyApp, method: &str, params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "dl.list" => Ok(Value::Array(
+            (0..app.inner.len())
+                .map(|index| download_stats(app, index))
+                .collect(),
+        )),
+        "dl.stats" => {
+            let index = gid_param(app, params)?;
+            Ok(download_stats(app, index))
+        }
+        "dl.pause" => {
+            let index = gid_param(app, params)?;
+            let core = &mut app.inner[index];
+            core.queued = false;
+            core.file.status.0.send(false).unwrap();
+            Ok(json!("OK"))
+        }
+        "dl.resume" => {
+            let index = gid_param(app, params)?;
+            enqueue(&mut app.inner[index]);
+            Ok(json!("OK"))
+        }
+        "dl.remove" => {
+            let index = gid_param(app, params)?;
+            let core = app.inner.remove(index);
+            core.file.status.0.send(false).unwrap();
+            Ok(json!("OK"))
+        }
+        method if method.starts_with("aria2.") => dispatch_aria2(app, method, params),
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Method not found: {}", method),
+        )),
+    }
+}
+
+pub fn handle_rpc_calls(app: &mut MyApp) {
+    while let Ok(call) = app.rpc.calls.1.try_recv() {
+        let result = dispatch(app, &call.method, &call.params);
+        let _ = call.reply.send(result);
+    }
+}
@@ -0,0 +1,420 @@
+use std::{fs, sync::Arc, thread::sleep, time::Duration};
+
+use eframe::egui::mutex::Mutex;
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, Time};
+
+use crate::{
+    config::{config_path, write_config_file},
+    history::{local_offset, now},
+    metadata::save_metadata,
+    queue::{enqueue, file_key, is_active},
+    MyApp,
+};
+
+const SCHEDULE_FILE: &str = "schedule.json";
+const TIMER_INTERVAL: Duration = Duration::from_secs(15);
+pub const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScheduleAction {
+    // In Mbs, replaces the global limit while the rule applies
+    Limit(f64),
+    PauseAll,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    // Monday first
+    pub days: [bool; 7],
+    // Minutes since midnight, a rule ending before it starts runs past midnight
+    pub start: u16,
+    pub end: u16,
+    pub action: ScheduleAction,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Schedule {
+    pub enabled: bool,
+    pub rules: Vec<ScheduleRule>,
+}
+
+pub struct Scheduler {
+    pub schedule: Arc<Mutex<Schedule>>,
+    // Written by the timer thread, applied by the ui
+    pub current: Arc<Mutex<Option<ScheduleAction>>>,
+    applied: Option<ScheduleAction>,
+    // Downloads the schedule paused, resumed once the pause window ends
+    paused: Vec<String>,
+    started: bool,
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            schedule: Arc::new(Mutex::new(Schedule::default())),
+            current: Arc::new(Mutex::new(None)),
+            applied: None,
+            paused: Vec::new(),
+            started: false,
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn holds(&self, key: &str) -> bool {
+        self.paused.iter().any(|paused| paused == key)
+    }
+
+    pub fn pauses_all(&self) -> bool {
+        self.applied == Some(ScheduleAction::PauseAll)
+    }
+}
+
+impl ScheduleRule {
+    fn matches(&self, weekday: usize, minute: u16) -> bool {
+        let yesterday = (weekday + 6) % 7;
+        if self.start <= self.end {
+            self.days[weekday] && self.start <= minute && minute < self.end
+        } else {
+            (self.days[weekday] && minute >= self.start)
+                || (self.days[yesterday] && minute < self.end)
+        }
+    }
+}
+
+impl Schedule {
+    // The first matching rule wins
+    pub fn action_at(&self, time: OffsetDateTime) -> Option<ScheduleAction> {
+        if !self.enabled {
+            return None;
+        }
+        let weekday = time.weekday().number_days_from_monday() as usize;
+        let minute = time.hour() as u16 * 60 + time.minute() as u16;
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(weekday, minute))
+            .map(|rule| rule.action.clone())
+    }
+
+    pub fn current_action(&self) -> Option<ScheduleAction> {
+        self.action_at(OffsetDateTime::now_utc().to_offset(local_offset()))
+    }
+}
+
+pub fn format_minutes(minutes: u16) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+pub fn parse_minutes(text: &str) -> Result<u16, String> {
+    let invalid = || format!("Invalid time: {} (expected HH:MM)", text);
+    let (hours, minutes) = text.trim().split_once(':').ok_or_else(invalid)?;
+    let hours = hours.parse::<u16>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<u16>().map_err(|_| invalid())?;
+    // 24:00 is allowed so a rule can run until the end of the day
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+// The first moment after `after` the clock shows HH:MM
+fn next_occurrence(text: &str, after: OffsetDateTime) -> Result<OffsetDateTime, String> {
+    let minutes = parse_minutes(text)? % (24 * 60);
+    let time =
+        Time::from_hms((minutes / 60) as u8, (minutes % 60) as u8, 0).map_err(|e| e.to_string())?;
+    let mut next = after.replace_time(time);
+    if next <= after {
+        next += time::Duration::days(1);
+    }
+    Ok(next)
+}
+
+// Unix times of a download's start and stop, the stop is the first one after the start so 23:00 to 01:00 runs overnight
+pub fn parse_start_stop(start: &str, stop: &str) -> Result<(Option<u64>, Option<u64>), String> {
+    let now = OffsetDateTime::now_utc().to_offset(local_offset());
+    let start_at = match start.trim() {
+        "" => None,
+        start => Some(next_occurrence(start, now)?),
+    };
+    let stop_at = match stop.trim() {
+        "" => None,
+        stop => Some(next_occurrence(stop, start_at.unwrap_or(now))?),
+    };
+    let same = |start: &str, stop: &str| -> Result<bool, String> {
+        Ok(parse_minutes(start)? % (24 * 60) == parse_minutes(stop)? % (24 * 60))
+    };
+    if start_at.is_some() && stop_at.is_some() && same(start, stop)? {
+        return Err("Start and stop cannot be the same time".to_string());
+    }
+    Ok((
+        start_at.map(|time| time.unix_timestamp() as u64),
+        stop_at.map(|time| time.unix_timestamp() as u64),
+    ))
+}
+
+pub fn load_schedule() -> Scheduler {
+    let schedule = fs::read_to_string(config_path(SCHEDULE_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Schedule>(&content).ok())
+        .unwrap_or_default();
+    let current = schedule.current_action();
+    Scheduler {
+        schedule: Arc::new(Mutex::new(schedule)),
+        current: Arc::new(Mutex::new(current)),
+        ..Default::default()
+    }
+}
+
+pub fn save_schedule(app: &mut MyApp, schedule: Schedule) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(&schedule).map_err(|e| e.to_string())?;
+    write_config_file(SCHEDULE_FILE, &json)?;
+    // Applied right away instead of waiting for the next tick
+    *app.scheduler.current.lock() = schedule.current_action();
+    *app.scheduler.schedule.lock() = schedule;
+    Ok(())
+}
+
+// Keeps evaluating the schedule even when nothing else wakes the ui
+pub fn start_schedule_timer(app: &mut MyApp, ctx: &eframe::egui::Context) {
+    if app.scheduler.started {
+        return;
+    }
+    app.scheduler.started = true;
+    let schedule = app.scheduler.schedule.clone();
+    let current = app.scheduler.current.clone();
+    let ctx = ctx.clone();
+    std::thread::spawn(move || loop {
+        let action = schedule.lock().current_action();
+        let changed = {
+            let mut current = current.lock();
+            let changed = *current != action;
+            *current = action;
+            changed
+        };
+        if changed {
+            ctx.request_repaint();
+        }
+        sleep(TIMER_INTERVAL);
+    });
+}
+
+// The global limit in Mbs, a scheduled limit takes precedence over the settings
+pub fn global_limit(app: &MyApp) -> f64 {
+    match &app.scheduler.applied {
+        Some(ScheduleAction::Limit(limit)) => *limit,
+        _ => app.settings.global_bandwidth,
+    }
+}
+
+pub fn process_schedule(app: &mut MyApp) {
+    let current = app.scheduler.current.lock().clone();
+    if current == app.scheduler.applied {
+        return;
+    }
+    let was_paused = app.scheduler.applied == Some(ScheduleAction::PauseAll);
+    let pause = current == Some(ScheduleAction::PauseAll);
+    if pause && !was_paused {
+        // Same as "Pause all", but remembers what to bring back
+        for core in app.inner.iter_mut() {
+            if core.queued || is_active(core) {
+                app.scheduler.paused.push(file_key(&core.file));
+                core.queued = false;
+                core.file.status.0.send(false).unwrap();
+            }
+        }
+    } else if !pause && was_paused {
+        for core in app.inner.iter_mut() {
+            if app.scheduler.paused.contains(&file_key(&core.file)) {
+                enqueue(core);
+            }
+        }
+        app.scheduler.paused.clear();
+    }
+    app.scheduler.applied = current;
+}
+
+// Starts and stops single downloads once their time comes, the times are cleared after firing
+pub fn process_download_timers(app: &mut MyApp) {
+    let now = now();
+    for core in app.inner.iter_mut() {
+        let start = core.metadata.start_at.is_some_and(|start| start <= now);
+        let stop = core.metadata.stop_at.is_some_and(|stop| stop <= now);
+        if !start && !stop {
+            continue;
+        }
+        if start {
+            core.metadata.start_at = None;
+            enqueue(core);
+        }
+        if stop {
+            core.metadata.stop_at = None;
+            core.queued = false;
+            if is_active(core) {
+                core.file.switch_status().unwrap();
+            }
+        }
+        if let Err(e) = save_metadata(&core.file, &core.metadata) {
+            app.popus.error.value = e;
+            app.popus.error.show = true;
+        }
+    }
+}
+
+// Editable copy of a rule, times stay text until the schedule is saved
+#[derive(Debug, Clone)]
+pub struct RuleForm {
+    pub days: [bool; 7],
+    pub start: String,
+    pub end: String,
+    pub pause: bool,
+    pub limit: f64,
+}
+impl Default for RuleForm {
+    fn default() -> Self {
+        Self {
+            days: [true, true, true, true, true, false, false],
+            start: "09:00".to_string(),
+            end: "17:00".to_string(),
+            pause: false,
+            limit: 1.0,
+        }
+    }
+}
+impl From<&ScheduleRule> for RuleForm {
+    fn from(rule: &ScheduleRule) -> Self {
+        let (pause, limit) = match rule.action {
+            ScheduleAction::Limit(limit) => (false, limit),
+            ScheduleAction::PauseAll => (true, 1.0),
+        };
+        Self {
+            days: rule.days,
+            start: format_minutes(rule.start),
+            end: format_minutes(rule.end),
+            pause,
+            limit,
+        }
+    }
+}
+impl RuleForm {
+    pub fn to_rule(&self) -> Result<ScheduleRule, String> {
+        if !self.days.contains(&true) {
+            return Err("Every rule needs at least one day".to_string());
+        }
+        let start = parse_minutes(&self.start)?;
+        let end = parse_minutes(&self.end)?;
+        if start == end {
+            return Err("A rule cannot start and end at the same time".to_string());
+        }
+        let action = if self.pause {
+            ScheduleAction::PauseAll
+        } else {
+            ScheduleAction::Limit(self.limit)
+        };
+        Ok(ScheduleRule {
+            days: self.days,
+            start,
+            end,
+            action,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(days: [bool; 7], start: &str, end: &str) -> ScheduleRule {
+        ScheduleRule {
+            days,
+            start: parse_minutes(start).unwrap(),
+            end: parse_minutes(end).unwrap(),
+            action: ScheduleAction::PauseAll,
+        }
+    }
+
+    const MONDAY: [bool; 7] = [true, false, false, false, false, false, false];
+    const SUNDAY: [bool; 7] = [false, false, false, false, false, false, true];
+
+    #[test]
+    fn daytime_window_ends_before_its_end() {
+        let rule = window(MONDAY, "09:00", "17:00");
+        assert!(!rule.matches(0, 8 * 60 + 59));
+        assert!(rule.matches(0, 9 * 60));
+        assert!(rule.matches(0, 16 * 60 + 59));
+        assert!(!rule.matches(0, 17 * 60));
+        assert!(!rule.matches(1, 12 * 60));
+    }
+
+    #[test]
+    fn window_crossing_midnight_belongs_to_the_day_it_starts() {
+        let rule = window(MONDAY, "23:00", "02:00");
+        assert!(rule.matches(0, 23 * 60 + 30));
+        assert!(rule.matches(1, 60));
+        assert!(!rule.matches(1, 2 * 60));
+        assert!(!rule.matches(1, 23 * 60 + 30));
+        assert!(!rule.matches(0, 60));
+        // Sunday night runs into Monday
+        assert!(window(SUNDAY, "22:00", "01:00").matches(0, 30));
+    }
+
+    #[test]
+    fn equal_start_and_end_never_matches() {
+        let rule = window([true; 7], "10:00", "10:00");
+        assert!((0..24 * 60).all(|minute| !rule.matches(0, minute)));
+    }
+
+    #[test]
+    fn times_are_hours_and_minutes() {
+        assert_eq!(parse_minutes("07:05").unwrap(), 425);
+        assert_eq!(parse_minutes("24:00").unwrap(), 1440);
+        assert!(parse_minutes("24:01").is_err());
+        assert!(parse_minutes("12:60").is_err());
+        assert!(parse_minutes("noon").is_err());
+    }
+
+    fn at(hour: u8, minute: u8) -> OffsetDateTime {
+        OffsetDateTime::UNIX_EPOCH.replace_time(Time::from_hms(hour, minute, 0).unwrap())
+    }
+
+    #[test]
+    fn next_occurrence_is_strictly_later() {
+        assert_eq!(next_occurrence("10:30", at(9, 0)).unwrap(), at(10, 30));
+        assert_eq!(
+            next_occurrence("08:00", at(9, 0)).unwrap(),
+            at(8, 0) + time::Duration::days(1)
+        );
+        assert_eq!(
+            next_occurrence("09:00", at(9, 0)).unwrap(),
+            at(9, 0) + time::Duration::days(1)
+        );
+        // 24:00 is midnight of the next day
+        assert_eq!(
+            next_occurrence("24:00", at(9, 0)).unwrap(),
+            at(0, 0) + time::Duration::days(1)
+        );
+    }
+
+    #[test]
+    fn stop_comes_after_start_even_across_midnight() {
+        let (start, stop) = parse_start_stop("23:00", "01:00").unwrap();
+        assert_eq!(stop.unwrap() - start.unwrap(), 2 * 60 * 60);
+        let (start, stop) = parse_start_stop("01:00", "23:00").unwrap();
+        assert_eq!(stop.unwrap() - start.unwrap(), 22 * 60 * 60);
+    }
+
+    #[test]
+    fn equal_start_and_stop_are_rejected() {
+        assert!(parse_start_stop("10:00", "10:00").is_err());
+        assert!(parse_start_stop("00:00", "24:00").is_err());
+    }
+
+    #[test]
+    fn start_and_stop_are_optional() {
+        assert_eq!(parse_start_stop("", "").unwrap(), (None, None));
+        let (start, stop) = parse_start_stop(" ", "12:00").unwrap();
+        assert_eq!(start, None);
+        assert!(stop.unwrap() > now());
+        assert!(parse_start_stop("25:00", "").is_err());
+    }
+}
@@ -1,8 +1,13 @@
-use std::{net::TcpStream, thread::sleep, time::Duration};
+use eframe::egui::{Align, Color32, DragValue, Layout, Response, Separator, Ui};
 
-use eframe::egui::{Align, Color32, Layout, Response, Separator, Ui};
-
-use crate::MyApp;
+use crate::{
+    bandwidth::{format_limit, mbs_to_bytes},
+    config::save_settings,
+    extern_windows::open_schedule,
+    history::format_timestamp,
+    schedule::ScheduleAction,
+    MyApp,
+};
 
 pub fn display_status_bar(ctx: &eframe::egui::Context, app: &mut MyApp) {
     eframe::egui::TopBottomPanel::bottom("status").show(ctx, |ui| {
@@ -24,15 +29,6 @@ pub fn display_status_bar(ctx: &eframe::egui::Context, app: &mut MyApp) {
             }
             total
         };
-        if !app.connected_to_net.started {
-            let safe = app.connected_to_net.connected.clone();
-            std::thread::spawn(move || loop {
-                let connected = is_connected();
-                *safe.lock() = connected;
-                sleep(Duration::from_secs(2));
-            });
-        }
-        app.connected_to_net.started = true;
         ui.with_layout(Layout::left_to_right(Align::Min), |ui| {
             // Left side: connection status
             let status = {
@@ -47,28 +43,71 @@ pub fn display_status_bar(ctx: &eframe::egui::Context, app: &mut MyApp) {
                 state
             };
             let connected = *app.connected_to_net.connected.lock();
+            let changed = *app.connected_to_net.changed.lock();
             ui.add_space(20.0);
             display_transfer_rate(ui, transfer_rate, status, connected);
             ui.add_space(20.0);
             ui.add(Separator::grow(Separator::default(), ui.available_height()));
-            ui.add_space(ui.available_width() - 100.0);
+            ui.add_space(15.0);
+            display_global_limit(ui, app);
+            ui.add_space(15.0);
+            ui.add(Separator::grow(Separator::default(), ui.available_height()));
+            ui.add_space(ui.available_width() - 240.0);
             ui.add(Separator::grow(Separator::default(), ui.available_height()));
             ui.add_space(15.0);
-            display_connection_status(ui, connected);
+            display_connection_status(ui, connected, changed);
         });
     });
 }
 
-fn is_connected() -> bool {
-    TcpStream::connect_timeout(&("8.8.8.8:53").parse().unwrap(), Duration::from_secs(2)).is_ok()
+fn display_global_limit(ui: &mut Ui, app: &mut MyApp) {
+    let text = match app.scheduler.current.lock().clone() {
+        Some(ScheduleAction::PauseAll) => "Paused by schedule".to_string(),
+        Some(ScheduleAction::Limit(limit)) => {
+            format!(
+                "Global limit: {} (scheduled)",
+                format_limit(mbs_to_bytes(limit))
+            )
+        }
+        None => format!(
+            "Global limit: {}",
+            format_limit(mbs_to_bytes(app.settings.global_bandwidth))
+        ),
+    };
+    ui.menu_button(text, |ui| {
+        let res = ui.add(
+            DragValue::new(&mut app.settings.global_bandwidth)
+                .range(0.0..=100_000.0)
+                .speed(0.1)
+                .suffix(" MB/s"),
+        );
+        let unlimited = ui.button("Unlimited").clicked();
+        if unlimited {
+            app.settings.global_bandwidth = 0.0;
+        }
+        if res.drag_stopped() || res.lost_focus() || unlimited {
+            if let Err(e) = save_settings(&app.settings) {
+                app.popus.error.value = e;
+                app.popus.error.show = true;
+            }
+        }
+        if ui.button("Edit schedule").clicked() {
+            open_schedule(app);
+            ui.close_menu();
+        }
+    });
 }
 
 // Function to display the connection status
-fn display_connection_status(ui: &mut Ui, connected: bool) {
+fn display_connection_status(ui: &mut Ui, connected: bool, changed: Option<u64>) {
+    let since = match changed {
+        Some(_) => format!(" since {}", format_timestamp(changed)),
+        None => String::default(),
+    };
     if connected {
-        ui.colored_label(Color32::GREEN, "Connected");
+        ui.colored_label(Color32::GREEN, format!("Connected{}", since));
     } else {
-        ui.colored_label(Color32::RED, "Disconnected");
+        ui.colored_label(Color32::RED, format!("Disconnected{}", since));
     }
 }
 
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::{mpsc, oneshot, watch},
+    task::LocalSet,
+};
+
+use crate::{
+    errors::DownloadError,
+    mirrors::take_over,
+    queue::file_key,
+    retry::{download_task, BoxedTask},
+    Core, MyApp,
+};
+
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+// Threads driving the jobs, a job mostly waits on the network so a few carry any number of them
+const WORKERS: usize = 4;
+
+type Job = Box<dyn FnOnce() -> BoxedTask + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Queued,
+    Running,
+    Paused,
+    Failed,
+    Done,
+}
+impl TaskState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskState::Queued => "Queued",
+            TaskState::Running => "Running",
+            TaskState::Paused => "Paused",
+            TaskState::Failed => "Failed",
+            TaskState::Done => "Done",
+        }
+    }
+}
+
+pub fn task_state(core: &Core) -> TaskState {
+    if core.file.complete.load(Ordering::Relaxed) {
+        TaskState::Done
+    } else if core.error.is_some() {
+        TaskState::Failed
+    } else if *core.file.status.1.borrow() {
+        TaskState::Running
+    } else if core.queued {
+        TaskState::Queued
+    } else {
+        TaskState::Paused
+    }
+}
+
+struct Task {
+    finished: Arc<AtomicBool>,
+    // Sending or dropping it ends the task at its next await
+    cancel: oneshot::Sender<()>,
+    status: watch::Sender<bool>,
+    // Once finished, how it ended
+    state: TaskState,
+}
+
+// A thread with its own LocalSet, jobs are spawned on it and never move
+struct Worker {
+    jobs: mpsc::UnboundedSender<Job>,
+    load: Arc<AtomicUsize>,
+}
+impl Worker {
+    fn new(runtime: Handle, number: usize) -> Self {
+        let (jobs, mut receiver) = mpsc::unbounded_channel::<Job>();
+        std::thread::Builder::new()
+            .name(format!("dl-worker-{}", number))
+            .spawn(move || {
+                let local = LocalSet::new();
+                runtime.block_on(local.run_until(async move {
+                    while let Some(job) = receiver.recv().await {
+                        tokio::task::spawn_local(job());
+                    }
+                }));
+            })
+            .unwrap();
+        Self {
+            jobs,
+            load: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+// Owns the only runtime of the ui, every download and background request runs on it
+pub struct Supervisor {
+    runtime: Option<Runtime>,
+    workers: Vec<Worker>,
+    tasks: HashMap<String, Task>,
+}
+impl Default for Supervisor {
+    fn default() -> Self {
+        let runtime = Runtime::new().unwrap();
+        let workers = (0..WORKERS)
+            .map(|number| Worker::new(runtime.handle().clone(), number))
+            .collect();
+        Self {
+            runtime: Some(runtime),
+            workers,
+            tasks: HashMap::new(),
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn handle(&self) -> Handle {
+        self.runtime.as_ref().unwrap().handle().clone()
+    }
+
+    // The engine's futures are not Send, so a job is created and driven on the least busy worker.
+    // The returned flag is set once the job finished or was cancelled
+    pub fn run<F, Fut>(&self, cancel: oneshot::Receiver<()>, job: F) -> Arc<AtomicBool>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let finished = Arc::new(AtomicBool::new(false));
+        let worker = match self
+            .workers
+            .iter()
+            .min_by_key(|worker| worker.load.load(Ordering::Relaxed))
+        {
+            Some(worker) => worker,
+            None => {
+                finished.store(true, Ordering::Relaxed);
+                return finished;
+            }
+        };
+        let load = worker.load.clone();
+        let done = finished.clone();
+        load.fetch_add(1, Ordering::Relaxed);
+        let job: Job = Box::new(move || {
+            Box::pin(async move {
+                tokio::select! {
+                    _ = job() => {}
+                    _ = cancel => {}
+                }
+                load.fetch_sub(1, Ordering::Relaxed);
+                done.store(true, Ordering::Relaxed);
+            })
+        });
+        // Only fails once the workers are gone, which happens at shutdown
+        if worker.jobs.send(job).is_err() {
+            finished.store(true, Ordering::Relaxed);
+        }
+        finished
+    }
+
+    pub fn state(&self, key: &str) -> Option<TaskState> {
+        self.tasks.get(key).map(|task| task.state)
+    }
+
+    // Pausing first lets the engine stop whatever it spawned on its own
+    pub fn cancel(&mut self, key: &str) {
+        if let Some(task) = self.tasks.remove(key) {
+            let _ = task.status.send(false);
+            let _ = task.cancel.send(());
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        for (_, task) in self.tasks.drain() {
+            let _ = task.status.send(false);
+            let _ = task.cancel.send(());
+        }
+        // Closing the channels lets every worker return, dropping what it still ran
+        self.workers.clear();
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        }
+    }
+}
+
+// Starts the downloads that were resumed, keeps how the finished ones ended and stops the ones removed from the list
+pub fn supervise(app: &mut MyApp) {
+    let keys = app
+        .inner
+        .iter()
+        .map(|core| file_key(&core.file))
+        .collect::<Vec<String>>();
+    let removed = app
+        .supervisor
+        .tasks
+        .keys()
+        .filter(|key| !keys.contains(key))
+        .cloned()
+        .collect::<Vec<String>>();
+    for key in removed {
+        app.supervisor.cancel(&key);
+    }
+    for (core, key) in app.inner.iter_mut().zip(keys) {
+        let state = task_state(core);
+        if let Some(task) = app.supervisor.tasks.get_mut(&key) {
+            let finished = task.finished.load(Ordering::Relaxed);
+            // A finished task stays until the download is started again, a failure ends up as Failed
+            if !finished || state != TaskState::Running || core.started {
+                task.state = state;
+                continue;
+            }
+        }
+        if state != TaskState::Running || core.started {
+            continue;
+        }
+        // The engine never goes through a proxy, with one set every download is fetched in chunks
+        if app.settings.proxy.is_set() && !core.metadata.segmented() {
+            if let Err(e) = take_over(&core.file, &mut core.metadata) {
+                let _ = core.file.status.0.send(false);
+                core.error = Some(DownloadError::Other(e));
+                continue;
+            }
+        }
+        let job = download_task(
+            core,
+            &app.credentials,
+            app.retry_policy,
+            app.connected_to_net.connected.clone(),
+            &app.settings.proxy,
+            &app.bucket,
+        );
+        let (cancel, cancelled) = oneshot::channel();
+        let finished = app.supervisor.run(cancelled, job);
+        app.supervisor.tasks.insert(
+            key,
+            Task {
+                finished,
+                cancel,
+                status: core.file.status.0.clone(),
+                state,
+            },
+        );
+        core.started = true;
+    }
+}